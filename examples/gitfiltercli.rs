@@ -1,5 +1,9 @@
 use gumdrop::Options;
 use gitfilter::filter::*;
+use gitfilter::replace_text::ReplaceText;
+use gitfilter::mailmap::Mailmap;
+use gitfilter::commit_map::{read_commit_map, read_ref_map, add_pruned_ref_tips};
+use gitfilter::blob_store::DEFAULT_BLOB_MEMORY_LIMIT;
 use std::path::PathBuf;
 
 #[derive(Debug, Options, Default)]
@@ -17,9 +21,57 @@ pub struct Filter {
     #[options(help = "Force git-fast-export to print out all of the blob data. This will make parsing a bit slower")]
     pub with_data: bool,
 
-    #[options(help = "Name of branch to filter from")]
+    #[options(help = "when using --with-data, blobs are held until a kept commit uses them. this is the max number of bytes of held blobs to keep in memory before writing them to a temp file. defaults to 256M")]
+    pub blob_memory_limit: Option<usize>,
+
+    #[options(help = "keep every held blob in memory, no matter how much memory that uses")]
+    pub no_blob_memory_limit: bool,
+
+    #[options(help = "remove every blob bigger than this size, eg: 500K, 10M, 1G. the paths that used them are printed to stderr")]
+    pub strip_blobs_bigger_than: Option<String>,
+
+    #[options(help = "file of blob ids to remove, one per line. the paths that used them are printed to stderr")]
+    pub strip_blobs_with_ids: Option<String>,
+
+    #[options(help = "file of text to replace in every blob. uses the same format as git-filter-repo --replace-text (literal, glob:, regex:, ==> replacements)")]
+    pub replace_text: Option<String>,
+
+    #[options(help = "when using --replace-text, dont replace anything in binary blobs")]
+    pub replace_text_skip_binary: bool,
+
+    #[options(help = "file of text to replace in every commit and tag message. uses the same format as --replace-text")]
+    pub replace_message: Option<String>,
+
+    #[options(help = "file in the .mailmap format to rewrite author, committer, and tagger names and emails")]
+    pub mailmap: Option<String>,
+
+    #[options(help = "rewrite author, committer, and tagger names and emails using the .mailmap of the repository")]
+    pub use_mailmap: bool,
+
+    #[options(help = "Name of branch to filter from. defaults to the branch that HEAD points to")]
     pub branch: Option<String>,
 
+    #[options(help = "a ref to filter, or a glob of refs like refs/heads/release/*. can be given multiple times to filter several refs in one run")]
+    pub refs: Vec<String>,
+
+    #[options(help = "filter every ref in the repository")]
+    pub all: bool,
+
+    #[options(help = "dont filter commits reachable from this revision, eg: the commit of the last sync. can be given multiple times")]
+    pub exclude_revs: Vec<String>,
+
+    #[options(help = "a commit-map from an earlier run, used to rewire the parents of commits whose parents were excluded")]
+    pub previous_commit_map: Option<String>,
+
+    #[options(help = "the ref-map from the same earlier run as --previous-commit-map. this knows where the refs were left when their tips were pruned")]
+    pub previous_ref_map: Option<String>,
+
+    #[options(help = "import the filtered history into this repository with git fast-import instead of printing it, and write a git-filter-repo style commit-map and ref-map into this directory")]
+    pub write_maps_to: Option<String>,
+
+    #[options(help = "import the filtered history into this repository with git fast-import instead of printing it, and rewrite the commit hashes in commit messages to the new hashes")]
+    pub rewrite_commit_hashes: bool,
+
     #[options(help = "path to filter")]
     pub path: Option<String>,
 
@@ -29,6 +81,42 @@ pub struct Filter {
     #[options(help = "path to exclude filter")]
     pub exclude_path: Option<String>,
 
+    #[options(help = "how --path, --exclude-path, and --path-rename match paths. one of: prefix (default), dir, exact. 'dir' only matches whole directories, so --path core does not match core-utils/")]
+    pub match_mode: Option<String>,
+
+    #[options(help = "file of paths to filter, one per line. uses the same format as git-filter-repo --paths-from-file (glob:, regex:, ==> renames, # comments)")]
+    pub paths_from_file: Option<String>,
+
+    #[options(help = "only keep the history of this directory, and make it the new root")]
+    pub subdirectory_filter: Option<String>,
+
+    #[options(help = "keep everything, but move the entire history into this directory")]
+    pub to_subdirectory_filter: Option<String>,
+
+    #[options(help = "only keep commits where this regex matches the author as 'Name <email>'")]
+    pub author_include: Option<String>,
+
+    #[options(help = "drop commits where this regex matches the author as 'Name <email>'")]
+    pub author_exclude: Option<String>,
+
+    #[options(help = "only keep commits where this regex matches the committer as 'Name <email>'")]
+    pub committer_include: Option<String>,
+
+    #[options(help = "drop commits where this regex matches the committer as 'Name <email>'")]
+    pub committer_exclude: Option<String>,
+
+    #[options(help = "only keep commits committed before this time, in seconds since the epoch")]
+    pub before: Option<i64>,
+
+    #[options(help = "only keep commits committed at or after this time, in seconds since the epoch")]
+    pub after: Option<i64>,
+
+    #[options(help = "what to do with commits that have no changes: always, auto (default), never. auto only prunes commits that became empty because of filtering")]
+    pub prune_empty: Option<String>,
+
+    #[options(help = "what to do with merge commits that become degenerate: always, auto (default), never")]
+    pub prune_degenerate: Option<String>,
+
     #[options(help = "the default is to implicitly exclude everything, by using --default-include you implicitly INCLUDE everything, and can explicitly choose to exclude specific paths")]
     pub default_include: bool,
 }
@@ -49,6 +137,16 @@ pub fn get_cli_input() -> Filter {
     cli
 }
 
+fn parse_prune_mode(mode: Option<String>) -> PruneEmpty {
+    match mode {
+        Some(mode) => match mode.parse::<PruneEmpty>() {
+            Ok(m) => m,
+            Err(e) => panic!("{:?}", e),
+        },
+        None => PruneEmpty::Auto,
+    }
+}
+
 fn main() {
     use std::io::stdout;
     let filter = get_cli_input();
@@ -65,17 +163,93 @@ fn main() {
     let filter_opts = FilterOptions {
         stream: stdout(),
         branch: filter.branch,
+        refs: if filter.all {
+            vec!["--all".into()]
+        } else {
+            filter.refs
+        },
+        exclude_revs: filter.exclude_revs,
+        previous_commit_map: match filter.previous_commit_map {
+            Some(map_file) => match read_commit_map(&map_file) {
+                Ok(mut commit_map) => {
+                    if let Some(ref_map_file) = filter.previous_ref_map {
+                        match read_ref_map(&ref_map_file) {
+                            Ok(ref_map) => add_pruned_ref_tips(&mut commit_map, &ref_map),
+                            Err(e) => panic!("Failed to load the ref map from {}: {}", ref_map_file, e),
+                        }
+                    }
+                    commit_map
+                }
+                Err(e) => panic!("Failed to load the commit map from {}: {}", map_file, e),
+            },
+            None => Default::default(),
+        },
         default_include: filter.default_include,
         with_blobs: filter.with_data,
+        blob_memory_limit: if filter.no_blob_memory_limit {
+            None
+        } else {
+            Some(filter.blob_memory_limit.unwrap_or(DEFAULT_BLOB_MEMORY_LIMIT))
+        },
+        strip_blobs_bigger_than: filter.strip_blobs_bigger_than.map(|size| match parse_size(&size) {
+            Ok(s) => s,
+            Err(e) => panic!("{:?}", e),
+        }),
+        strip_blobs_with_ids: match filter.strip_blobs_with_ids {
+            Some(ids_file) => match load_blob_ids_from_file(&ids_file) {
+                Ok(ids) => ids,
+                Err(e) => panic!("Failed to load blob ids from {}: {}", ids_file, e),
+            },
+            None => Default::default(),
+        },
+        replace_text: match filter.replace_text {
+            Some(replace_file) => match ReplaceText::load_from_file(&replace_file) {
+                Ok(mut replace_text) => {
+                    replace_text.skip_binary = filter.replace_text_skip_binary;
+                    Some(replace_text)
+                }
+                Err(e) => panic!("Failed to load replacements from {}: {}", replace_file, e),
+            },
+            None => None,
+        },
+        replace_message: match filter.replace_message {
+            Some(replace_file) => match ReplaceText::load_from_file(&replace_file) {
+                Ok(replace_message) => Some(replace_message),
+                Err(e) => panic!("Failed to load message replacements from {}: {}", replace_file, e),
+            },
+            None => None,
+        },
+        mailmap: match (filter.mailmap, filter.use_mailmap) {
+            (Some(mailmap_file), _) => match Mailmap::load_from_file(&mailmap_file) {
+                Ok(mailmap) => Some(mailmap),
+                Err(e) => panic!("Failed to load mailmap from {}: {}", mailmap_file, e),
+            },
+            (None, true) => match Mailmap::load_from_repo(None::<PathBuf>) {
+                Ok(mailmap) => Some(mailmap),
+                Err(e) => panic!("Failed to load the mailmap of the repository: {}", e),
+            },
+            (None, false) => None,
+        },
+        prune_empty: parse_prune_mode(filter.prune_empty),
+        prune_degenerate: parse_prune_mode(filter.prune_degenerate),
+        write_maps_to: filter.write_maps_to.map(PathBuf::from),
+        rewrite_commit_hashes: filter.rewrite_commit_hashes,
     };
     let mut filter_rules = vec![];
+    let match_mode = match filter.match_mode {
+        Some(mode) => match mode.parse::<PathMatchMode>() {
+            Ok(m) => m,
+            Err(e) => panic!("{:?}", e),
+        },
+        None => PathMatchMode::Prefix,
+    };
 
     // include has precedence over exclude
     if let Some(filter_include) = filter.path {
-        filter_rules.push(FilterRulePathInclude(filter_include));
+        filter_rules.push(FilterRulePathInclude(PathPattern::new(filter_include, match_mode)));
     }
     if let Some(filter_exclude) = filter.exclude_path {
-        filter_rules.push(FilterRulePathExclude(filter_exclude));
+        filter_rules.push(FilterRulePathExclude(PathPattern::new(filter_exclude, match_mode)));
     }
     if let Some(filter_rename) = filter.path_rename {
         let mut split = filter_rename.split(':');
@@ -83,15 +257,60 @@ fn main() {
         let dest = split.next();
         match (src, dest) {
             (Some(src), Some(dest)) => {
-                filter_rules.push(FilterRulePathRename(src.into(), dest.into()));
+                filter_rules.push(FilterRulePathRename(PathPattern::new(src, match_mode), dest.into()));
             },
             _ => panic!("Must provide a src:dest when using --path-rename"),
         }
     }
+    if let Some(dir) = filter.subdirectory_filter {
+        filter_rules.push(FilterRuleSubdirectoryFilter(dir));
+    }
+    if let Some(paths_file) = filter.paths_from_file {
+        match load_filter_rules_from_file(&paths_file) {
+            Ok(file_rules) => filter_rules.extend(file_rules),
+            Err(e) => panic!("Failed to load rules from {}: {}", paths_file, e),
+        }
+    }
+    if let Some(regex_str) = filter.author_include {
+        filter_rules.push(FilterRuleAuthorInclude(regex_str));
+    }
+    if let Some(regex_str) = filter.author_exclude {
+        filter_rules.push(FilterRuleAuthorExclude(regex_str));
+    }
+    if let Some(regex_str) = filter.committer_include {
+        filter_rules.push(FilterRuleCommitterInclude(regex_str));
+    }
+    if let Some(regex_str) = filter.committer_exclude {
+        filter_rules.push(FilterRuleCommitterExclude(regex_str));
+    }
+    if let Some(before) = filter.before {
+        filter_rules.push(FilterRuleCommittedBefore(before));
+    }
+    if let Some(after) = filter.after {
+        filter_rules.push(FilterRuleCommittedAfter(after));
+    }
+    // this goes last because it applies to everything
+    if let Some(dir) = filter.to_subdirectory_filter {
+        filter_rules.push(FilterRuleToSubdirectoryFilter(dir));
+    }
     if filter_rules.len() == 0 {
         panic!("Must provide either a filter include or an exclude");
     }
 
+    // the maps and the rewritten hashes need the
+    // new oids, so the history has to be imported
+    let direct = filter_opts.write_maps_to.is_some() || filter_opts.rewrite_commit_hashes;
     let no_location: Option<PathBuf> = None;
-    let _ = filter_with_rules(filter_opts, filter_rules, no_location);
+    let res = if direct {
+        filter_with_rules_direct(filter_opts, filter_rules)
+    } else {
+        filter_with_rules(filter_opts, filter_rules, no_location)
+    };
+    let filter_state = match res {
+        Ok(s) => s,
+        Err(e) => panic!("Failed to filter: {}", e),
+    };
+    for path in filter_state.stripped_paths {
+        eprintln!("Stripped blob from: {}", path);
+    }
 }
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use super::export_parser::StructuredBlob;
+
+/// the default max number of bytes of held blobs to keep in memory
+pub const DEFAULT_BLOB_MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+
+static SPILL_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+enum PendingBlobData {
+    InMemory(Vec<u8>),
+    /// (offset, length) in the spill file
+    Spilled(u64, usize),
+}
+
+struct PendingBlob {
+    original_oid: String,
+    data: PendingBlobData,
+}
+
+/// a temp file that blobs get appended to. it gets deleted when dropped
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl SpillFile {
+    fn new() -> io::Result<SpillFile> {
+        let file_name = format!(
+            "gitfilter-blobs-{}-{}",
+            std::process::id(),
+            SPILL_FILE_COUNTER.fetch_add(1, Ordering::SeqCst),
+        );
+        let path = std::env::temp_dir().join(file_name);
+        let file = OpenOptions::new()
+            .read(true).write(true).create_new(true)
+            .open(&path)?;
+        Ok(SpillFile { path, file, len: 0 })
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<u64> {
+        let offset = self.len;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(offset)
+    }
+
+    /// forget everything after this offset, so
+    /// that the next append writes over it
+    fn truncate(&mut self, offset: u64) -> io::Result<()> {
+        self.file.set_len(offset)?;
+        self.len = offset;
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// holds blobs that we have seen, but that no kept commit
+/// has referenced yet. fast-export outputs a blob before the first
+/// commit that uses it, but we dont know if that commit will be kept
+/// until we filter it, so the blob has to wait here.
+/// blobs that are never taken out are never written.
+///
+/// if a memory limit is given, blobs that would go over the limit
+/// are written to a temp file instead. without a limit, every
+/// held blob stays in memory. the space of a spilled blob on disk
+/// is reused once it was the last one appended, or once
+/// there are no spilled blobs left.
+pub struct PendingBlobs {
+    blobs: HashMap<String, PendingBlob>,
+    memory_limit: Option<usize>,
+    bytes_in_memory: usize,
+    spill_file: Option<SpillFile>,
+    spilled_blobs: usize,
+}
+
+impl PendingBlobs {
+    pub fn new(memory_limit: Option<usize>) -> PendingBlobs {
+        PendingBlobs {
+            blobs: HashMap::new(),
+            memory_limit,
+            bytes_in_memory: 0,
+            spill_file: None,
+            spilled_blobs: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+
+    /// how many bytes of blob data are currently held in memory.
+    /// this does not include the spilled blobs.
+    pub fn bytes_in_memory(&self) -> usize {
+        self.bytes_in_memory
+    }
+
+    /// store this blob until a commit references its mark.
+    /// a blob without a mark can never be referenced, so
+    /// it is not stored, and false is returned.
+    pub fn insert(&mut self, blob: StructuredBlob) -> io::Result<bool> {
+        let mark = match blob.mark {
+            Some(mark) => mark,
+            None => return Ok(false),
+        };
+        let fits_in_memory = match self.memory_limit {
+            Some(limit) => self.bytes_in_memory + blob.data.len() <= limit,
+            None => true,
+        };
+        let data = if fits_in_memory {
+            self.bytes_in_memory += blob.data.len();
+            PendingBlobData::InMemory(blob.data)
+        } else {
+            if self.spill_file.is_none() {
+                self.spill_file = Some(SpillFile::new()?);
+            }
+            // we just set it above
+            let spill_file = self.spill_file.as_mut().unwrap();
+            let offset = spill_file.append(&blob.data)?;
+            self.spilled_blobs += 1;
+            PendingBlobData::Spilled(offset, blob.data.len())
+        };
+        let pending = PendingBlob { original_oid: blob.original_oid, data };
+        match self.blobs.insert(mark, pending) {
+            Some(PendingBlob { data: PendingBlobData::InMemory(old), .. }) => {
+                self.bytes_in_memory -= old.len();
+            }
+            Some(PendingBlob { data: PendingBlobData::Spilled(offset, len), .. }) => {
+                self.free_spilled(offset, len)?;
+            }
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// this spilled blob is not needed anymore. the spill file can
+    /// only give back space at its end, so if this was the last spilled
+    /// blob, or it was at the end of the file, the file is truncated.
+    fn free_spilled(&mut self, offset: u64, len: usize) -> io::Result<()> {
+        self.spilled_blobs -= 1;
+        if let Some(spill_file) = self.spill_file.as_mut() {
+            if self.spilled_blobs == 0 {
+                spill_file.truncate(0)?;
+            } else if offset + len as u64 == spill_file.len {
+                spill_file.truncate(offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// if we are holding a blob with this mark, remove it
+    /// and return it so that it can be written.
+    pub fn take(&mut self, mark: &str) -> io::Result<Option<StructuredBlob>> {
+        let pending = match self.blobs.remove(mark) {
+            Some(pending) => pending,
+            None => return Ok(None),
+        };
+        let data = match pending.data {
+            PendingBlobData::InMemory(data) => {
+                self.bytes_in_memory -= data.len();
+                data
+            }
+            PendingBlobData::Spilled(offset, len) => {
+                let data = match self.spill_file.as_mut() {
+                    Some(spill_file) => spill_file.read(offset, len)?,
+                    None => return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Blob {} was spilled, but there is no spill file", mark),
+                    )),
+                };
+                self.free_spilled(offset, len)?;
+                data
+            }
+        };
+        Ok(Some(StructuredBlob {
+            mark: Some(mark.to_string()),
+            original_oid: pending.original_oid,
+            data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_blob(mark: &str, data: &str) -> StructuredBlob {
+        StructuredBlob {
+            mark: Some(mark.into()),
+            original_oid: format!("oid{}", mark),
+            data: data.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn blobs_can_be_taken_once() {
+        let mut pending = PendingBlobs::new(None);
+        assert!(pending.insert(make_blob(":1", "hello")).unwrap());
+        assert!(!pending.insert(StructuredBlob::default()).unwrap());
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.bytes_in_memory(), 5);
+
+        let blob = pending.take(":1").unwrap().unwrap();
+        assert_eq!(blob.data, b"hello");
+        assert_eq!(blob.original_oid, "oid:1");
+        assert!(pending.take(":1").unwrap().is_none());
+        assert!(pending.is_empty());
+        assert_eq!(pending.bytes_in_memory(), 0);
+    }
+
+    #[test]
+    fn blobs_over_the_memory_limit_get_spilled() {
+        let mut pending = PendingBlobs::new(Some(6));
+        pending.insert(make_blob(":1", "abc")).unwrap();
+        pending.insert(make_blob(":2", "defg")).unwrap();
+        pending.insert(make_blob(":3", "hij")).unwrap();
+        pending.insert(make_blob(":4", "klmnop")).unwrap();
+        // only :1 and :3 fit in memory
+        assert_eq!(pending.bytes_in_memory(), 6);
+
+        assert_eq!(pending.take(":4").unwrap().unwrap().data, b"klmnop");
+        assert_eq!(pending.take(":1").unwrap().unwrap().data, b"abc");
+        assert_eq!(pending.take(":2").unwrap().unwrap().data, b"defg");
+        assert_eq!(pending.take(":3").unwrap().unwrap().data, b"hij");
+        assert_eq!(pending.bytes_in_memory(), 0);
+    }
+
+    #[test]
+    fn spilled_space_is_reused() {
+        let spill_len = |pending: &PendingBlobs| pending.spill_file.as_ref().unwrap().len;
+        let mut pending = PendingBlobs::new(Some(0));
+        pending.insert(make_blob(":1", "abc")).unwrap();
+        pending.insert(make_blob(":2", "defg")).unwrap();
+        assert_eq!(spill_len(&pending), 7);
+
+        // :1 is not at the end, so its space cant be given back yet
+        assert_eq!(pending.take(":1").unwrap().unwrap().data, b"abc");
+        assert_eq!(spill_len(&pending), 7);
+        pending.insert(make_blob(":3", "hi")).unwrap();
+        assert_eq!(spill_len(&pending), 9);
+        // :3 was the last one appended
+        assert_eq!(pending.take(":3").unwrap().unwrap().data, b"hi");
+        assert_eq!(spill_len(&pending), 7);
+        // nothing is spilled anymore, so the file is empty
+        assert_eq!(pending.take(":2").unwrap().unwrap().data, b"defg");
+        assert_eq!(spill_len(&pending), 0);
+        let path = pending.spill_file.as_ref().unwrap().path.clone();
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process::Stdio;
+use super::filter_state::FilterState;
+
+/// git wont abbreviate a hash to less than this
+const MIN_ABBREV_LEN: usize = 7;
+/// the length of a full hash in a sha1 repository
+pub const SHA1_HASH_LEN: usize = 40;
+/// the length of a full hash in a sha256 repository
+pub const SHA256_HASH_LEN: usize = 64;
+
+/// the length of a full hash in the repository at the location
+/// (or the current directory), which depends on its object format
+pub fn read_hash_len<P: AsRef<Path>>(location: Option<P>) -> io::Result<usize> {
+    let exe_and_args = ["git", "rev-parse", "--show-object-format"];
+    let child = exechelper::spawn_with_env_ex2(
+        &exe_and_args,
+        &[], &[],
+        location,
+        Some(Stdio::null()),
+        Some(Stdio::null()),
+        Some(Stdio::piped()),
+    )?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("Failed to read the object format of the repository"));
+    }
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "sha1" => Ok(SHA1_HASH_LEN),
+        "sha256" => Ok(SHA256_HASH_LEN),
+        format => Err(io::Error::other(format!("Unknown object format: {}", format))),
+    }
+}
+
+/// find the (start, end) of every word in the text that looks like
+/// a full or abbreviated commit hash: 7 to hash_len lowercase hex digits,
+/// with no letters or digits directly before or after it.
+pub fn find_hash_like_words(text: &str, hash_len: usize) -> Vec<(usize, usize)> {
+    let bytes = text.as_bytes();
+    let mut words = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_alphanumeric() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+            i += 1;
+        }
+        let word = &bytes[start..i];
+        let is_hex = word.iter().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+        if is_hex && word.len() >= MIN_ABBREV_LEN && word.len() <= hash_len {
+            words.push((start, i));
+        }
+    }
+    words
+}
+
+/// ask git fast-import for the oid of a mark that it has already
+/// imported. the stream has to be fast-import's stdin, and
+/// the responses have to be its stdout (or its `--cat-blob-fd`).
+pub fn get_mark_from_fast_import<T: Write>(
+    stream: &mut T,
+    responses: &mut dyn BufRead,
+    mark: &str,
+    hash_len: usize,
+) -> io::Result<String> {
+    stream.write_all(format!("get-mark {}\n", mark).as_bytes())?;
+    stream.flush()?;
+    let mut line = String::new();
+    responses.read_line(&mut line)?;
+    let oid = line.trim_end();
+    if oid.len() != hash_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected response from git fast-import for get-mark {}: {}", mark, oid),
+        ));
+    }
+    Ok(oid.to_string())
+}
+
+/// replace every hash in the message that refers to a commit we have
+/// seen with the new oid of that commit, abbreviated to the same length.
+/// hashes that are ambiguous, unknown, or refer to a pruned commit are
+/// left alone. new oids that are not in the filter_state yet are
+/// looked up with `new_oid_of_mark`, and remembered.
+/// hash_len is the length of a full hash, see `read_hash_len`.
+/// returns true if the message changed.
+pub fn rewrite_commit_hashes(
+    message: &mut String,
+    filter_state: &mut FilterState,
+    hash_len: usize,
+    new_oid_of_mark: &mut dyn FnMut(&str) -> io::Result<String>,
+) -> io::Result<bool> {
+    let words = find_hash_like_words(message, hash_len);
+    if words.is_empty() {
+        return Ok(false);
+    }
+    let mut rewritten = String::with_capacity(message.len());
+    let mut last_end = 0;
+    let mut changed = false;
+    for (start, end) in words {
+        let word = &message[start..end];
+        let mark = match filter_state.find_commit_by_oid_prefix(word) {
+            Some(mark) => mark.to_string(),
+            None => continue,
+        };
+        // only kept commits have a new oid
+        if filter_state.mark_map.get(&mark) != Some(&mark) {
+            continue;
+        }
+        let new_oid = match filter_state.new_commit_oids.get(&mark) {
+            Some(oid) => oid.clone(),
+            None => {
+                let oid = new_oid_of_mark(&mark)?;
+                filter_state.new_commit_oids.insert(mark, oid.clone());
+                oid
+            }
+        };
+        let new_word = &new_oid[..word.len()];
+        if new_word != word {
+            rewritten.push_str(&message[last_end..start]);
+            rewritten.push_str(new_word);
+            last_end = end;
+            changed = true;
+        }
+    }
+    if changed {
+        rewritten.push_str(&message[last_end..]);
+        *message = rewritten;
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::export_parser::StructuredCommit;
+
+    #[test]
+    fn finds_hash_like_words() {
+        let text = "reverts abc1234. see 0123456789abcdef0123456789abcdef01234567, not abc123 or abc1234x or ABC1234";
+        let words: Vec<&str> = find_hash_like_words(text, SHA1_HASH_LEN).iter().map(|(s, e)| &text[*s..*e]).collect();
+        assert_eq!(words, vec!["abc1234", "0123456789abcdef0123456789abcdef01234567"]);
+
+        let sha256 = "0123456789abcdef".repeat(4);
+        let text = format!("see {}", sha256);
+        assert!(find_hash_like_words(&text, SHA1_HASH_LEN).is_empty());
+        assert_eq!(find_hash_like_words(&text, SHA256_HASH_LEN), vec![(4, 68)]);
+    }
+
+    #[test]
+    fn rewrites_hashes_of_kept_commits() {
+        let mut filter_state = FilterState::default();
+        let mut record = |mark: &str, oid: &str, kept_as: &str| {
+            let commit = StructuredCommit {
+                mark: Some(mark.into()),
+                original_oid: oid.into(),
+                ..Default::default()
+            };
+            filter_state.record_commit(&commit);
+            filter_state.mark_map.insert(mark.into(), kept_as.into());
+        };
+        record(":1", &format!("abc1234{}", "0".repeat(33)), ":1");
+        record(":2", &format!("def5678{}", "0".repeat(33)), ":1");
+        record(":3", &format!("aaaaaaa1{}", "0".repeat(32)), ":3");
+        record(":4", &format!("aaaaaaa2{}", "0".repeat(32)), ":4");
+
+        let mut message = format!(
+            "reverts abc1234\nfixes abc1234{}\npruned def5678, ambiguous aaaaaaa\n",
+            "0".repeat(33),
+        );
+        let mut lookups = vec![];
+        let mut lookup = |mark: &str| -> io::Result<String> {
+            lookups.push(mark.to_string());
+            Ok("1".repeat(40))
+        };
+        assert!(rewrite_commit_hashes(&mut message, &mut filter_state, SHA1_HASH_LEN, &mut lookup).unwrap());
+        assert_eq!(message, format!(
+            "reverts 1111111\nfixes {}\npruned def5678, ambiguous aaaaaaa\n",
+            "1".repeat(40),
+        ));
+        // the new oid was remembered after the first lookup
+        assert_eq!(lookups, vec![":1"]);
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+use std::path::Path;
+use std::process::Stdio;
+use super::filter_state::FilterState;
+
+/// what a pruned commit (or deleted ref) maps to in a sha1
+/// repository. a sha256 repository uses 64 zeros. see `zero_oid_like`
+pub const ZERO_OID: &str = "0000000000000000000000000000000000000000";
+
+/// the zero oid that has the same length as this oid,
+/// so that it works for both sha1 and sha256 repositories
+pub fn zero_oid_like(oid: &str) -> String {
+    if oid.is_empty() {
+        return ZERO_OID.to_string();
+    }
+    "0".repeat(oid.len())
+}
+
+/// true for the zero oid of any object format
+pub fn is_zero_oid(oid: &str) -> bool {
+    !oid.is_empty() && oid.bytes().all(|b| b == b'0')
+}
+
+/// read a marks file from `git fast-import --export-marks`.
+/// each line is `:<mark> <oid>`. returns mark -> oid
+pub fn parse_marks(text: &str) -> HashMap<String, String> {
+    let mut marks = HashMap::new();
+    for line in text.lines() {
+        let mut split = line.split(' ');
+        if let (Some(mark), Some(oid)) = (split.next(), split.next()) {
+            marks.insert(mark.to_string(), oid.to_string());
+        }
+    }
+    marks
+}
+
+pub fn read_marks_file<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(parse_marks(&text))
+}
+
+/// parse a commit-map that was written by `write_commit_map`
+/// (or by git-filter-repo). returns old oid -> new oid, where
+/// the new oid is the zero oid for commits that were pruned
+pub fn parse_commit_map(text: &str) -> HashMap<String, String> {
+    let mut commit_map = HashMap::new();
+    for line in text.lines() {
+        let mut split = line.split_whitespace();
+        if let (Some(old_oid), Some(new_oid)) = (split.next(), split.next()) {
+            // skip the header
+            if old_oid == "old" {
+                continue;
+            }
+            commit_map.insert(old_oid.to_string(), new_oid.to_string());
+        }
+    }
+    commit_map
+}
+
+pub fn read_commit_map<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(parse_commit_map(&text))
+}
+
+/// parse a ref-map that was written by `write_ref_map`
+/// (or by git-filter-repo). returns old oid -> new oid
+pub fn parse_ref_map(text: &str) -> HashMap<String, String> {
+    // the first two columns are the same as in the commit-map
+    parse_commit_map(text)
+}
+
+pub fn read_ref_map<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(parse_ref_map(&text))
+}
+
+/// the commit-map maps pruned commits to the zero oid, so a later
+/// run cant know what their children should use as a parent instead.
+/// but the ref-map has the nearest kept ancestor of the tip of every
+/// ref, so pruned tips (like the commit of the last sync) can map to that.
+pub fn add_pruned_ref_tips(
+    commit_map: &mut HashMap<String, String>,
+    ref_map: &HashMap<String, String>,
+) {
+    for (old_oid, new_oid) in ref_map {
+        if is_zero_oid(new_oid) {
+            continue;
+        }
+        if let Some(mapto) = commit_map.get_mut(old_oid) {
+            if is_zero_oid(mapto) {
+                *mapto = new_oid.clone();
+            }
+        }
+    }
+}
+
+/// ref name -> oid of every ref in the repository
+/// at the location (or the current directory)
+pub fn read_current_refs<P: AsRef<Path>>(location: Option<P>) -> io::Result<HashMap<String, String>> {
+    let exe_and_args = ["git", "for-each-ref", "--format=%(refname) %(objectname)"];
+    let child = exechelper::spawn_with_env_ex2(
+        &exe_and_args,
+        &[], &[],
+        location,
+        Some(Stdio::null()),
+        Some(Stdio::null()),
+        Some(Stdio::piped()),
+    )?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("Failed to list the refs of the repository"));
+    }
+    let mut refs = HashMap::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some((ref_name, oid)) = line.split_once(' ') {
+            refs.insert(ref_name.to_string(), oid.to_string());
+        }
+    }
+    Ok(refs)
+}
+
+/// the new oid of a commit from the original stream. if the
+/// commit was kept, its mark is in the marks file. otherwise it was
+/// pruned, and it gets the zero oid.
+pub fn new_commit_oid(
+    filter_state: &FilterState,
+    marks: &HashMap<String, String>,
+    mark: &str,
+) -> String {
+    let zero_oid = || {
+        let old_oid = filter_state.commit_mark_to_oid.get(mark).map(|oid| oid.as_str());
+        zero_oid_like(old_oid.unwrap_or_default())
+    };
+    match filter_state.mark_map.get(mark) {
+        Some(mapto) if mapto == mark => match marks.get(mark) {
+            Some(oid) => oid.clone(),
+            None => zero_oid(),
+        },
+        _ => zero_oid(),
+    }
+}
+
+/// the new oid that a ref should point to. if every commit of
+/// the ref was pruned, it gets the zero oid. refs that dont point to
+/// a commit in the stream (like annotated tags) are looked up
+/// in current_refs, which is ref name -> oid after the import.
+pub fn new_ref_oid(
+    filter_state: &FilterState,
+    marks: &HashMap<String, String>,
+    current_refs: &HashMap<String, String>,
+    ref_name: &str,
+) -> String {
+    let tip = match filter_state.ref_tips.get(ref_name) {
+        Some(tip) => tip,
+        None => return ZERO_OID.to_string(),
+    };
+    let zero_oid = || zero_oid_like(&tip.original_oid);
+    match &tip.mark {
+        // the ref points to the nearest kept ancestor of its original tip
+        Some(mark) => match filter_state.mark_map.get(mark) {
+            Some(mapto) if !mapto.is_empty() => marks.get(mapto).cloned()
+                .unwrap_or_else(zero_oid),
+            _ => zero_oid(),
+        },
+        None => current_refs.get(ref_name).cloned()
+            .unwrap_or_else(zero_oid),
+    }
+}
+
+/// the header of the maps is padded to the length of the oids,
+/// which is 40 for sha1 and 64 for sha256
+fn oid_width(filter_state: &FilterState) -> usize {
+    let any_oid = filter_state.commit_mark_to_oid.values()
+        .chain(filter_state.ref_tips.values().map(|tip| &tip.original_oid))
+        .next();
+    match any_oid {
+        Some(oid) if !oid.is_empty() => oid.len(),
+        _ => ZERO_OID.len(),
+    }
+}
+
+/// write a git-filter-repo compatible commit-map:
+/// a header line, and then `<old oid> <new oid>` for every commit
+pub fn write_commit_map<W: Write>(
+    filter_state: &FilterState,
+    marks: &HashMap<String, String>,
+    out: W,
+) -> io::Result<()> {
+    let mut out = out;
+    writeln!(out, "{:<width$} new", "old", width = oid_width(filter_state))?;
+    // sorted by mark so that its in the same order as the stream
+    let mut commit_marks: Vec<&String> = filter_state.commit_mark_to_oid.keys().collect();
+    commit_marks.sort_by_key(|mark| mark.trim_start_matches(':').parse::<usize>().unwrap_or(0));
+    for mark in commit_marks {
+        let old_oid = &filter_state.commit_mark_to_oid[mark];
+        writeln!(out, "{} {}", old_oid, new_commit_oid(filter_state, marks, mark))?;
+    }
+    Ok(())
+}
+
+/// write a git-filter-repo compatible ref-map:
+/// a header line, and then `<old oid> <new oid> <ref>` for every ref
+pub fn write_ref_map<W: Write>(
+    filter_state: &FilterState,
+    marks: &HashMap<String, String>,
+    current_refs: &HashMap<String, String>,
+    out: W,
+) -> io::Result<()> {
+    let mut out = out;
+    let width = oid_width(filter_state);
+    writeln!(out, "{:<width$} {:<width$} ref", "old", "new", width = width)?;
+    for (ref_name, tip) in &filter_state.ref_tips {
+        let new_oid = new_ref_oid(filter_state, marks, current_refs, ref_name);
+        writeln!(out, "{} {} {}", tip.original_oid, new_oid, ref_name)?;
+    }
+    Ok(())
+}
+
+/// write the `commit-map` and `ref-map` files into this directory
+pub fn write_maps_to_dir<P: AsRef<Path>>(
+    dir: P,
+    filter_state: &FilterState,
+    marks: &HashMap<String, String>,
+    current_refs: &HashMap<String, String>,
+) -> io::Result<()> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    let mut commit_map = BufWriter::new(File::create(dir.join("commit-map"))?);
+    write_commit_map(filter_state, marks, &mut commit_map)?;
+    commit_map.flush()?;
+    let mut ref_map = BufWriter::new(File::create(dir.join("ref-map"))?);
+    write_ref_map(filter_state, marks, current_refs, &mut ref_map)?;
+    ref_map.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::filter_state::RefTip;
+
+    #[test]
+    fn writes_filter_repo_style_maps() {
+        let mut filter_state = FilterState::default();
+        filter_state.commit_mark_to_oid.insert(":1".into(), "a".repeat(40));
+        filter_state.commit_mark_to_oid.insert(":2".into(), "b".repeat(40));
+        filter_state.commit_mark_to_oid.insert(":10".into(), "c".repeat(40));
+        // :2 was pruned, so it maps to its parent
+        filter_state.mark_map.insert(":1".into(), ":1".into());
+        filter_state.mark_map.insert(":2".into(), ":1".into());
+        filter_state.mark_map.insert(":10".into(), ":10".into());
+        filter_state.ref_tips.insert("refs/heads/master".into(), RefTip {
+            original_oid: "b".repeat(40),
+            mark: Some(":2".into()),
+        });
+        filter_state.ref_tips.insert("refs/tags/v1".into(), RefTip {
+            original_oid: "d".repeat(40),
+            mark: None,
+        });
+        let marks = parse_marks(&format!(":1 {}\n:10 {}\n", "1".repeat(40), "3".repeat(40)));
+        let mut current_refs = HashMap::new();
+        current_refs.insert("refs/tags/v1".to_string(), "4".repeat(40));
+
+        let mut commit_map = vec![];
+        write_commit_map(&filter_state, &marks, &mut commit_map).unwrap();
+        let parsed = parse_commit_map(&String::from_utf8(commit_map.clone()).unwrap());
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[&"b".repeat(40)], ZERO_OID);
+        let expected = format!(
+            "old                                      new\n{} {}\n{} {}\n{} {}\n",
+            "a".repeat(40), "1".repeat(40),
+            "b".repeat(40), ZERO_OID,
+            "c".repeat(40), "3".repeat(40),
+        );
+        assert_eq!(String::from_utf8(commit_map).unwrap(), expected);
+
+        let mut ref_map = vec![];
+        write_ref_map(&filter_state, &marks, &current_refs, &mut ref_map).unwrap();
+        let expected = format!(
+            "old                                      new                                      ref\n{} {} refs/heads/master\n{} {} refs/tags/v1\n",
+            "b".repeat(40), "1".repeat(40),
+            "d".repeat(40), "4".repeat(40),
+        );
+        assert_eq!(String::from_utf8(ref_map).unwrap(), expected);
+    }
+
+    #[test]
+    fn sha256_maps_use_a_longer_zero_oid() {
+        let mut filter_state = FilterState::default();
+        filter_state.commit_mark_to_oid.insert(":1".into(), "a".repeat(64));
+        filter_state.commit_mark_to_oid.insert(":2".into(), "b".repeat(64));
+        filter_state.mark_map.insert(":1".into(), ":1".into());
+        filter_state.mark_map.insert(":2".into(), ":1".into());
+        filter_state.ref_tips.insert("refs/heads/master".into(), RefTip {
+            original_oid: "b".repeat(64),
+            mark: Some(":2".into()),
+        });
+        let marks = parse_marks(&format!(":1 {}\n", "1".repeat(64)));
+
+        let mut commit_map = vec![];
+        write_commit_map(&filter_state, &marks, &mut commit_map).unwrap();
+        let expected = format!(
+            "{:<64} new\n{} {}\n{} {}\n",
+            "old", "a".repeat(64), "1".repeat(64), "b".repeat(64), "0".repeat(64),
+        );
+        assert_eq!(String::from_utf8(commit_map.clone()).unwrap(), expected);
+
+        // the pruned tip maps to its kept ancestor through the ref-map
+        let mut ref_map = vec![];
+        write_ref_map(&filter_state, &marks, &HashMap::new(), &mut ref_map).unwrap();
+        let mut previous = parse_commit_map(&String::from_utf8(commit_map).unwrap());
+        add_pruned_ref_tips(&mut previous, &parse_ref_map(&String::from_utf8(ref_map).unwrap()));
+        assert_eq!(previous[&"b".repeat(64)], "1".repeat(64));
+        assert!(is_zero_oid(&"0".repeat(64)) && is_zero_oid(ZERO_OID));
+        assert!(!is_zero_oid(""));
+    }
+}
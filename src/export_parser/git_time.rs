@@ -0,0 +1,113 @@
+use std::fmt;
+
+/// a parsed version of the `<seconds> <+/-HHMM>` timestamp
+/// that git uses for authors, committers, and taggers.
+/// writing it with `to_string()` gives back the exact same text
+/// that was parsed, as long as it was in the canonical format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GitTime {
+    /// seconds since the unix epoch (in UTC)
+    pub seconds: i64,
+    /// the timezone offset of the person, in minutes east of UTC.
+    /// eg: +0200 is 120, and -0530 is -330
+    pub offset_minutes: i32,
+    /// git writes `-0000` when the timezone is unknown. we
+    /// keep track of it so that it can be written the same way.
+    negative_zero: bool,
+}
+
+impl GitTime {
+    pub fn new(seconds: i64, offset_minutes: i32) -> GitTime {
+        GitTime { seconds, offset_minutes, negative_zero: false }
+    }
+
+    /// parse text like `1617000000 +0200`. returns None if
+    /// the text is not in that format.
+    pub fn parse(text: &str) -> Option<GitTime> {
+        let mut split = text.split(' ');
+        let seconds: i64 = split.next()?.parse().ok()?;
+        let offset = split.next()?;
+        if split.next().is_some() || offset.len() != 5 {
+            return None;
+        }
+        let sign = match offset.as_bytes()[0] {
+            b'+' => 1,
+            b'-' => -1,
+            _ => return None,
+        };
+        let digits = &offset[1..];
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let hours: i32 = digits[..2].parse().ok()?;
+        let minutes: i32 = digits[2..].parse().ok()?;
+        if minutes >= 60 {
+            return None;
+        }
+        let offset_minutes = sign * (hours * 60 + minutes);
+        Some(GitTime {
+            seconds,
+            offset_minutes,
+            negative_zero: sign == -1 && offset_minutes == 0,
+        })
+    }
+
+    /// a point in time this many seconds later (or earlier if
+    /// negative), in the same timezone. None if that overflows
+    pub fn shifted_by(&self, seconds: i64) -> Option<GitTime> {
+        let seconds = self.seconds.checked_add(seconds)?;
+        Some(GitTime { seconds, ..*self })
+    }
+
+    /// the same point in time, but in a different timezone
+    pub fn with_offset(&self, offset_minutes: i32) -> GitTime {
+        GitTime::new(self.seconds, offset_minutes)
+    }
+
+    /// the seconds since the epoch in the timezone of the person.
+    /// useful for things like "what hour of the day was this"
+    pub fn local_seconds(&self) -> i64 {
+        self.seconds + (self.offset_minutes as i64) * 60
+    }
+}
+
+impl fmt::Display for GitTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.offset_minutes < 0 || self.negative_zero { '-' } else { '+' };
+        let offset = self.offset_minutes.abs();
+        write!(f, "{} {}{:02}{:02}", self.seconds, sign, offset / 60, offset % 60)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips_exactly() {
+        for text in &["1617000000 +0200", "1617000000 -0530", "0 +0000", "1617000000 -0000", "-100 +1400"] {
+            let time = GitTime::parse(text).unwrap();
+            assert_eq!(&time.to_string(), text);
+        }
+        let time = GitTime::parse("1617000000 -0530").unwrap();
+        assert_eq!(time.seconds, 1617000000);
+        assert_eq!(time.offset_minutes, -330);
+
+        assert!(GitTime::parse("1617000000").is_none());
+        assert!(GitTime::parse("1617000000 +02:00").is_none());
+        assert!(GitTime::parse("1617000000 0200").is_none());
+        assert!(GitTime::parse("1617000000 +0260").is_none());
+        assert!(GitTime::parse("abc +0200").is_none());
+    }
+
+    #[test]
+    fn can_do_date_math() {
+        let time = GitTime::parse("1617000000 +0200").unwrap();
+        assert_eq!(time.shifted_by(-3600).unwrap().to_string(), "1616996400 +0200");
+        assert!(time.shifted_by(i64::MAX).is_none());
+        assert_eq!(time.with_offset(0).to_string(), "1617000000 +0000");
+        assert_eq!(time.local_seconds(), 1617000000 + 7200);
+        let unknown_tz = GitTime::parse("1617000000 -0000").unwrap();
+        assert_eq!(unknown_tz.with_offset(0).to_string(), "1617000000 +0000");
+    }
+}
@@ -5,6 +5,9 @@ pub use structured_parse::*;
 pub mod unstructured_parse;
 pub use unstructured_parse::*;
 
+pub mod git_time;
+pub use git_time::*;
+
 
 use std::io::Error;
 use std::sync::mpsc;
@@ -13,13 +16,14 @@ use num_cpus;
 use std::collections::BinaryHeap;
 use std::cmp::Reverse;
 use std::io::Write;
-use std::{path::{PathBuf, Path}, io};
+use std::io::BufRead;
+use std::{path::Path, io};
 // use std::time::Instant;
 // use std::time::Duration;
 
 pub struct WaitObj {
     pub index: usize,
-    pub obj: StructuredExportObject,
+    pub obj: Result<StructuredExportObject, ParseError>,
 }
 
 impl PartialEq for WaitObj {
@@ -47,13 +51,94 @@ pub fn parse_git_filter_export<O, E, P: AsRef<Path>>(
     with_blobs: bool,
     location: Option<P>,
     cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), Error> {
+    let source = git_export_source(export_branch, with_blobs, location);
+    parse_fast_export_source(source, cb)
+}
+
+/// same as `parse_git_filter_export`, but reads the fast-export
+/// text from a stream instead of spawning git fast-export.
+pub fn parse_fast_export_stream<O, E, R: BufRead>(
+    reader: R,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
 ) -> Result<(), Error> {
     let mut cb = cb;
-    parse_git_filter_export_with_callback(export_branch, with_blobs, location, |unparsed| {
-        let parsed = parse_into_structured_object(unparsed);
-        cb(parsed)
-    })?;
-    Ok(())
+    let mut parse_error = None;
+    let res = parse_fast_export_stream_with_callback(
+        reader, parse_then_callback(&mut cb, &mut parse_error));
+    // the callback error is not very descriptive, so if
+    // it was caused by a parse error, return that instead
+    match parse_error {
+        Some(e) => Err(e.into()),
+        None => res,
+    }
+}
+
+pub fn parse_fast_export_source<O, E>(
+    source: FastExportSource,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), Error> {
+    let mut cb = cb;
+    let mut parse_error = None;
+    let res = source.parse_with_callback(parse_then_callback(&mut cb, &mut parse_error));
+    match parse_error {
+        Some(e) => Err(e.into()),
+        None => res,
+    }
+}
+
+/// wraps a structured callback so that it can be used as an
+/// unstructured callback. if parsing fails, the error is stored
+/// in parse_error, and the returned callback errors to stop the stream
+fn parse_then_callback<'a, O, E>(
+    cb: &'a mut impl FnMut(StructuredExportObject) -> Result<O, E>,
+    parse_error: &'a mut Option<ParseError>,
+) -> impl FnMut(UnparsedFastExportObject) -> Result<(), ()> + 'a {
+    move |unparsed| {
+        match parse_into_structured_object(unparsed) {
+            Ok(parsed) => cb(parsed).map(|_| ()).map_err(|_| ()),
+            Err(e) => {
+                *parse_error = Some(e);
+                Err(())
+            }
+        }
+    }
+}
+
+/// the error from one of the `_via_channel` parsers. either the
+/// callback failed, or the fast-export source could not be read/parsed
+enum ChannelError<E> {
+    Callback(E),
+    Source(Error),
+}
+
+/// the old `_via_channel` functions can only return the callback's error,
+/// so if the source fails they panic with the reason, like they always did.
+/// use the `_ex` variants to get that error back instead
+fn callback_error_or_panic<E>(res: Result<(), ChannelError<E>>) -> Result<(), E> {
+    match res {
+        Ok(()) => Ok(()),
+        Err(ChannelError::Callback(e)) => Err(e),
+        Err(ChannelError::Source(e)) => panic!("Failed to parse fast-export source: {}", e),
+    }
+}
+
+fn callback_or_source_error<E: From<Error>>(res: Result<(), ChannelError<E>>) -> Result<(), E> {
+    match res {
+        Ok(()) => Ok(()),
+        Err(ChannelError::Callback(e)) => Err(e),
+        Err(ChannelError::Source(e)) => Err(e.into()),
+    }
+}
+
+fn git_export_source<P: AsRef<Path>>(
+    export_branch: Option<String>,
+    with_blobs: bool,
+    location: Option<P>,
+) -> FastExportSource {
+    let location = location.map(|p| p.as_ref().to_path_buf());
+    let export_refs = export_branch.into_iter().collect();
+    FastExportSource::GitExport { export_refs, with_blobs, location }
 }
 
 pub fn parse_git_filter_export_via_channel_and_n_parsing_threads<O, E, P: AsRef<Path>>(
@@ -63,7 +148,73 @@ pub fn parse_git_filter_export_via_channel_and_n_parsing_threads<O, E, P: AsRef<
     location: Option<P>,
     cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
 ) -> Result<(), E> {
+    let source = git_export_source(export_branch, with_blobs, location);
+    parse_fast_export_source_via_channel_and_n_parsing_threads(source, n_parsing_threads, cb)
+}
+
+/// same as `parse_git_filter_export_via_channel_and_n_parsing_threads`,
+/// but a malformed stream or a failed git fast-export is returned as an error
+pub fn parse_git_filter_export_via_channel_and_n_parsing_threads_ex<O, E, P: AsRef<Path>>(
+    export_branch: Option<String>,
+    with_blobs: bool,
+    n_parsing_threads: usize,
+    location: Option<P>,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E>
+where E: From<Error>
+{
+    let source = git_export_source(export_branch, with_blobs, location);
+    parse_fast_export_source_via_channel_and_n_parsing_threads_ex(source, n_parsing_threads, cb)
+}
+
+pub fn parse_fast_export_stream_via_channel_and_n_parsing_threads<O, E, R: BufRead + Send + 'static>(
+    reader: R,
+    n_parsing_threads: usize,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E> {
+    let source = FastExportSource::from_stream(reader);
+    parse_fast_export_source_via_channel_and_n_parsing_threads(source, n_parsing_threads, cb)
+}
+
+pub fn parse_fast_export_stream_via_channel_and_n_parsing_threads_ex<O, E, R: BufRead + Send + 'static>(
+    reader: R,
+    n_parsing_threads: usize,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E>
+where E: From<Error>
+{
+    let source = FastExportSource::from_stream(reader);
+    parse_fast_export_source_via_channel_and_n_parsing_threads_ex(source, n_parsing_threads, cb)
+}
+
+pub fn parse_fast_export_source_via_channel_and_n_parsing_threads<O, E>(
+    source: FastExportSource,
+    n_parsing_threads: usize,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E> {
+    callback_error_or_panic(parse_via_n_parsing_threads(source, n_parsing_threads, cb))
+}
+
+pub fn parse_fast_export_source_via_channel_and_n_parsing_threads_ex<O, E>(
+    source: FastExportSource,
+    n_parsing_threads: usize,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E>
+where E: From<Error>
+{
+    callback_or_source_error(parse_via_n_parsing_threads(source, n_parsing_threads, cb))
+}
+
+fn parse_via_n_parsing_threads<O, E>(
+    source: FastExportSource,
+    n_parsing_threads: usize,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), ChannelError<E>> {
     let mut cb = cb;
+    let mut cb = |obj: Result<StructuredExportObject, ParseError>| {
+        let obj = obj.map_err(|e| ChannelError::Source(e.into()))?;
+        cb(obj).map_err(ChannelError::Callback)
+    };
     let mut spawned_threads = vec![];
     let (tx, rx) = mpsc::channel();
     for _ in 0..n_parsing_threads {
@@ -72,7 +223,11 @@ pub fn parse_git_filter_export_via_channel_and_n_parsing_threads<O, E, P: AsRef<
         let parse_thread = thread::spawn(move || {
             for (counter, received) in parse_rx {
                 let parsed = export_parser::parse_into_structured_object(received);
-                parse_consumer_tx_clone.send((counter, parsed)).unwrap();
+                // if the main thread stopped receiving, then
+                // theres no point in parsing anything else
+                if parse_consumer_tx_clone.send((counter, parsed)).is_err() {
+                    break;
+                }
             }
         });
         spawned_threads.push((parse_tx, parse_thread));
@@ -83,24 +238,19 @@ pub fn parse_git_filter_export_via_channel_and_n_parsing_threads<O, E, P: AsRef<
     // otherwise our program will hang.
     drop(tx);
 
-    let location: Option<PathBuf> = match location {
-        Some(p) => Some(p.as_ref().to_path_buf()),
-        None => None,
-    };
-
-    // on the thread that is running the git fast-export,
+    // on the thread that is reading the fast-export source,
     // it will alternate passing these UNPARSED messages to one of our
     // parsing threads. the parsing threads (created above)
     // will then pass the PARSED message back to our main thread
     let thread_handle = thread::spawn(move || {
         let mut counter = 0;
-        let _ = parse_git_filter_export_with_callback(export_branch, with_blobs, location, |x| {
+        source.parse_with_callback(|x| {
             let thread_index = counter % n_parsing_threads as usize;
             let (parse_tx, _) = &spawned_threads[thread_index];
             let res = parse_tx.send((counter, x));
             counter += 1;
             res
-        });
+        })
     });
 
     // eprintln!("Using threads {}", n_parsing_threads);
@@ -148,7 +298,9 @@ pub fn parse_git_filter_export_via_channel_and_n_parsing_threads<O, E, P: AsRef<
         }
     }
 
-    let _ = thread_handle.join().unwrap();
+    // if reading the source failed (eg: a data size that is not a number)
+    // the objects after it were never sent, so that has to be an error too
+    thread_handle.join().unwrap().map_err(ChannelError::Source)?;
     // eprintln!("Last received at {:?}", std::time::Instant::now());
 
     Ok(())
@@ -168,50 +320,110 @@ pub fn parse_git_filter_export_via_channel<O, E, P: AsRef<Path>>(
     location: Option<P>,
     cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
 ) -> Result<(), E> {
+    let source = git_export_source(export_branch, with_blobs, location);
+    parse_fast_export_source_via_channel(source, cb)
+}
+
+/// same as `parse_git_filter_export_via_channel`, but a malformed
+/// stream or a failed git fast-export is returned as an error
+pub fn parse_git_filter_export_via_channel_ex<O, E, P: AsRef<Path>>(
+    export_branch: Option<String>,
+    with_blobs: bool,
+    location: Option<P>,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E>
+where E: From<Error>
+{
+    let source = git_export_source(export_branch, with_blobs, location);
+    parse_fast_export_source_via_channel_ex(source, cb)
+}
+
+/// same as `parse_git_filter_export_via_channel`, but reads
+/// the fast-export text from a stream instead of spawning git fast-export.
+pub fn parse_fast_export_stream_via_channel<O, E, R: BufRead + Send + 'static>(
+    reader: R,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E> {
+    parse_fast_export_source_via_channel(FastExportSource::from_stream(reader), cb)
+}
+
+pub fn parse_fast_export_stream_via_channel_ex<O, E, R: BufRead + Send + 'static>(
+    reader: R,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E>
+where E: From<Error>
+{
+    parse_fast_export_source_via_channel_ex(FastExportSource::from_stream(reader), cb)
+}
+
+pub fn parse_fast_export_source_via_channel<O, E>(
+    source: FastExportSource,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E> {
+    callback_error_or_panic(parse_via_channel(source, cb))
+}
+
+pub fn parse_fast_export_source_via_channel_ex<O, E>(
+    source: FastExportSource,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), E>
+where E: From<Error>
+{
+    callback_or_source_error(parse_via_channel(source, cb))
+}
+
+fn parse_via_channel<O, E>(
+    source: FastExportSource,
+    cb: impl FnMut(StructuredExportObject) -> Result<O, E>,
+) -> Result<(), ChannelError<E>> {
     let mut cb = cb;
     let cpu_count = num_cpus::get() as isize;
     // minus 2 because we are already using 2 threads.
     let spawn_parser_threads = cpu_count - 2;
 
-    let location: Option<PathBuf> = match location {
-        Some(p) => Some(p.as_ref().to_path_buf()),
-        None => None,
-    };
-
     if spawn_parser_threads > 1 {
-        return parse_git_filter_export_via_channel_and_n_parsing_threads(
-            export_branch, with_blobs, spawn_parser_threads as usize, location, cb);
+        return parse_via_n_parsing_threads(
+            source, spawn_parser_threads as usize, cb);
     }
 
     // otherwise here we will use only 2 threads: on the main
     // thread we will run the parsing and filtering, and on the spawned
-    // thread we will be collecting and splitting the git fast-export output
+    // thread we will be collecting and splitting the fast-export output
     let (tx, rx) = mpsc::channel();
     let thread_handle = thread::spawn(move || {
-        parse_git_filter_export_with_callback(export_branch, with_blobs, location, |x| {
+        source.parse_with_callback(|x| {
             tx.send(x)
         })
     });
 
     for received in rx {
-        let parsed = parse_into_structured_object(received);
+        let parsed = parse_into_structured_object(received)
+            .map_err(|e| ChannelError::Source(e.into()))?;
         // here we know the order we receive is the exact same as the order
         // they were parsed, so we can callback right away.
-        cb(parsed)?;
+        cb(parsed).map_err(ChannelError::Callback)?;
     }
 
     // eprintln!("Counted {} objects from git fast-export", parsed_objects.len());
-    let _ = thread_handle.join().unwrap();
+    thread_handle.join().unwrap().map_err(ChannelError::Source)?;
     Ok(())
 }
 
 
 pub fn write_person_info(write_data: &mut Vec<u8>, person: &CommitPersonOwned, is_author: bool) {
     if is_author {
-        write_data.extend(b"author ");
+        write_person_line(write_data, b"author", person);
     } else {
-        write_data.extend(b"committer ");
+        write_person_line(write_data, b"committer", person);
     }
+}
+
+/// writes a line of the form:
+/// `<keyword> <name> <<email>> <when>`
+/// where keyword is one of author, committer, or tagger
+pub fn write_person_line(write_data: &mut Vec<u8>, keyword: &[u8], person: &CommitPersonOwned) {
+    write_data.extend(keyword);
+    write_data.push(b' ');
     if let Some(name) = &person.name {
         write_data.extend(name.as_bytes());
         write_data.push(b' ');
@@ -264,7 +476,7 @@ pub fn write_to_stream<W: Write>(stream: W, obj: StructuredExportObject) -> io::
         }
         write_person_info(&mut write_data, &commit_obj.committer, false);
         write_data.extend(b"data ");
-        write_data.extend(obj.data_size.as_bytes());
+        write_data.extend(commit_obj.commit_message.len().to_string().as_bytes());
         write_data.push(b'\n');
         write_data.extend(commit_obj.commit_message.as_bytes());
         write_data.push(b'\n');
@@ -329,10 +541,35 @@ pub fn write_to_stream<W: Write>(stream: W, obj: StructuredExportObject) -> io::
         write_data.extend(blob_obj.original_oid.as_bytes());
         write_data.push(b'\n');
         write_data.extend(b"data ");
-        write_data.extend(obj.data_size.as_bytes());
+        write_data.extend(blob_obj.data.len().to_string().as_bytes());
         write_data.push(b'\n');
         write_data.extend(blob_obj.data);
         write_data.push(b'\n');
+    } else if let StructuredObjectType::Tag(tag_obj) = obj.object_type {
+        write_data.extend(b"tag ");
+        write_data.extend(tag_obj.tag_name.as_bytes());
+        write_data.push(b'\n');
+        if let Some(mark) = &tag_obj.mark {
+            write_data.extend(b"mark ");
+            write_data.extend(mark.as_bytes());
+            write_data.push(b'\n');
+        }
+        write_data.extend(b"from ");
+        write_data.extend(tag_obj.from.as_bytes());
+        write_data.push(b'\n');
+        if let Some(oid) = &tag_obj.original_oid {
+            write_data.extend(b"original-oid ");
+            write_data.extend(oid.as_bytes());
+            write_data.push(b'\n');
+        }
+        if let Some(tagger) = &tag_obj.tagger {
+            write_person_line(&mut write_data, b"tagger", tagger);
+        }
+        write_data.extend(b"data ");
+        write_data.extend(tag_obj.tag_message.len().to_string().as_bytes());
+        write_data.push(b'\n');
+        write_data.extend(tag_obj.tag_message.as_bytes());
+        write_data.push(b'\n');
     }
 
     stream.write_all(&write_data)?;
@@ -343,6 +580,7 @@ pub fn write_to_stream<W: Write>(stream: W, obj: StructuredExportObject) -> io::
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::path::PathBuf;
     // use std::io::prelude::*;
 
     // TODO: whats a unit test? ;)
@@ -390,6 +628,176 @@ mod tests {
             }).unwrap();
     }
 
+    const STREAM_WITH_PROGRESS: &str = "feature done
+blob
+mark :1
+original-oid 78981922613b2afb6025042ff6bd878ac1994e85
+data 2
+a
+
+progress 1 objects
+reset refs/heads/master
+commit refs/heads/master
+mark :2
+original-oid dbb474e4e5fe94ddc75040aa83d0abab4172c9b3
+author agent <agent@example.com> 1792312127 +0000
+committer agent <agent@example.com> 1792312127 +0000
+data 4
+one
+M 100644 :1 a
+
+progress 2 objects
+commit refs/heads/master
+mark :3
+original-oid cc24c8211f8573b351ce75fcdab4110efa380394
+author agent <agent@example.com> 1792312127 +0000
+committer agent <agent@example.com> 1792312127 +0000
+data 4
+two
+from :2
+M 100644 :1 b
+
+progress 3 objects
+done
+";
+
+    fn collect_marks_from_stream(text: &str) -> Vec<String> {
+        let mut marks = vec![];
+        let reader = std::io::Cursor::new(text.to_string());
+        parse_fast_export_stream(reader, |obj| {
+            let mark = match obj.object_type {
+                StructuredObjectType::Commit(c) => c.mark,
+                StructuredObjectType::Blob(b) => b.mark,
+                _ => None,
+            };
+            marks.push(mark.unwrap());
+            if 1 == 2 {
+                return Err(());
+            }
+            Ok(())
+        }).unwrap();
+        marks
+    }
+
+    #[test]
+    fn can_parse_from_a_stream() {
+        let marks = collect_marks_from_stream(STREAM_WITH_PROGRESS);
+        assert_eq!(marks, vec![":1", ":2", ":3"]);
+    }
+
+    #[test]
+    fn can_parse_from_a_stream_without_progress_lines() {
+        let text: String = STREAM_WITH_PROGRESS.lines()
+            .filter(|l| !l.starts_with("progress "))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        let marks = collect_marks_from_stream(&text);
+        assert_eq!(marks, vec![":1", ":2", ":3"]);
+    }
+
+    #[test]
+    fn parsing_a_stream_with_multiple_threads_keeps_order_the_same() {
+        let reader = std::io::Cursor::new(STREAM_WITH_PROGRESS.to_string());
+        let mut messages = vec![];
+        parse_fast_export_stream_via_channel_and_n_parsing_threads(reader, 4, |obj| {
+            if let StructuredObjectType::Commit(c) = obj.object_type {
+                messages.push(c.commit_message);
+            }
+            if 1 == 2 {
+                return Err(());
+            }
+            Ok(())
+        }).unwrap();
+        assert_eq!(messages, vec!["one\n", "two\n"]);
+    }
+
+    #[test]
+    fn malformed_streams_return_errors_instead_of_panicking() {
+        let bad_line = STREAM_WITH_PROGRESS.replace("M 100644 :1 b", "Z 100644 :1 b");
+        let reader = std::io::Cursor::new(bad_line);
+        let res = parse_fast_export_stream_via_channel_and_n_parsing_threads_ex(reader, 4, |_| {
+            if 1 == 2 {
+                return Err(io::Error::from(io::ErrorKind::Other));
+            }
+            Ok(())
+        });
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Z 100644 :1 b"));
+
+        let bad_size = STREAM_WITH_PROGRESS.replace("data 4\ntwo", "data four\ntwo");
+        let reader = std::io::Cursor::new(bad_size);
+        let res = parse_fast_export_stream(reader, |_| {
+            if 1 == 2 {
+                return Err(());
+            }
+            Ok(())
+        });
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("cc24c8211f8573b351ce75fcdab4110efa380394"));
+    }
+
+    #[test]
+    fn resets_without_data_are_their_own_objects() {
+        let text = STREAM_WITH_PROGRESS.replace("objects\ndone\n", "objects
+reset refs/tags/v1
+from :2
+
+progress 4 objects
+reset refs/heads/other
+from :3
+");
+        let parse = |text: &str| {
+            let mut objects = vec![];
+            let reader = std::io::Cursor::new(text.to_string());
+            parse_fast_export_stream(reader, |obj| {
+                let mark = match obj.object_type {
+                    StructuredObjectType::Commit(c) => c.mark,
+                    StructuredObjectType::Blob(b) => b.mark,
+                    _ => None,
+                };
+                objects.push((mark, obj.has_reset, obj.has_reset_from));
+                if 1 == 2 {
+                    return Err(());
+                }
+                Ok(())
+            }).unwrap();
+            objects
+        };
+        let expected = vec![
+            (Some(":1".to_string()), None, None),
+            (Some(":2".to_string()), Some("refs/heads/master".to_string()), None),
+            (Some(":3".to_string()), None, None),
+            (None, Some("refs/tags/v1".to_string()), Some(":2".to_string())),
+            // the stream ended without a blank line or done
+            (None, Some("refs/heads/other".to_string()), Some(":3".to_string())),
+        ];
+        assert_eq!(parse(&text), expected);
+        let without_progress: String = text.lines()
+            .filter(|l| !l.starts_with("progress ") && !l.is_empty())
+            .map(|l| format!("{}\n", l))
+            .collect();
+        assert_eq!(parse(&without_progress), expected);
+    }
+
+    #[test]
+    fn lines_that_are_not_utf8_are_errors() {
+        let mut bytes = STREAM_WITH_PROGRESS.as_bytes().to_vec();
+        let name_at = STREAM_WITH_PROGRESS.find("agent <").unwrap();
+        bytes[name_at] = 0xe9;
+        let reader = std::io::Cursor::new(bytes);
+        let res = parse_fast_export_stream(reader, |_| {
+            if 1 == 2 {
+                return Err(());
+            }
+            Ok(())
+        });
+        let err = res.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("not valid utf8"));
+    }
+
     #[test]
     fn test1() {
         let now = std::time::Instant::now();
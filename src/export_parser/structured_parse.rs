@@ -1,8 +1,11 @@
 use super::UnparsedFastExportObject;
+use super::GitTime;
 use regex::Regex;
 use regex::Captures;
 use once_cell::sync::OnceCell;
 use std::str::SplitWhitespace;
+use std::fmt;
+use std::io;
 
 macro_rules! regex_capture {
     ($text:tt, $reg:tt) => {
@@ -16,27 +19,29 @@ macro_rules! regex_capture {
     };
 }
 
-pub fn get_regex_authorline(text: &str) -> Option<Captures> {
-    regex_capture!(text, r"^(?:author|committer) (.*?) ?<(.*?)> (.*?)$")
+pub fn get_regex_authorline(text: &str) -> Option<Captures<'_>> {
+    regex_capture!(text, r"^(?:author|committer|tagger) (.*?) ?<(.*?)> (.*?)$")
 }
 
-pub fn get_regex_filemodifyline(text: &str) -> Option<Captures> {
+pub fn get_regex_filemodifyline(text: &str) -> Option<Captures<'_>> {
     regex_capture!(text, r"^M ([0-9]*) (.*?) (.*?)$")
 }
 
-pub fn get_regex_filedeleteline(text: &str) -> Option<Captures> {
+pub fn get_regex_filedeleteline(text: &str) -> Option<Captures<'_>> {
     regex_capture!(text, r"^D (.*?)$")
 }
 
-pub fn get_regex_filecopyline(text: &str) -> Option<Captures> {
-    regex_capture!(text, r"^C (.*?) (.*?)$")
+// the source path of a copy/rename can be quoted, in which
+// case it can contain spaces, so we cant just split on the first space
+pub fn get_regex_filecopyline(text: &str) -> Option<Captures<'_>> {
+    regex_capture!(text, r#"^C ("(?:[^"\\]|\\.)*"|\S+) (.*?)$"#)
 }
 
-pub fn get_regex_filerenameline(text: &str) -> Option<Captures> {
-    regex_capture!(text, r"^R (.*?) (.*?)$")
+pub fn get_regex_filerenameline(text: &str) -> Option<Captures<'_>> {
+    regex_capture!(text, r#"^R ("(?:[^"\\]|\\.)*"|\S+) (.*?)$"#)
 }
 
-pub fn get_regex_notemodifyline(text: &str) -> Option<Captures> {
+pub fn get_regex_notemodifyline(text: &str) -> Option<Captures<'_>> {
     regex_capture!(text, r"^N (.*?) (.*?)$")
 }
 
@@ -47,6 +52,50 @@ pub fn owned_string_option(orig: Option<&str>) -> Option<String> {
     }
 }
 
+/// which part of a fast-export object we were
+/// parsing when we failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseSection {
+    /// everything up to and including the `data <size>` line
+    BeforeData,
+    /// the data section itself. only the size can fail to parse
+    Data,
+    /// the from/merge/fileops lines after the data section
+    AfterData,
+}
+
+/// returned instead of panicking when a line of the
+/// fast-export stream is malformed, or is something
+/// that this parser does not know about (eg: from a newer git version)
+#[derive(Debug)]
+pub struct ParseError {
+    /// the index of the object in the stream, starting at 0
+    pub object_index: usize,
+    /// the original-oid of the object if we parsed it before failing
+    pub original_oid: Option<String>,
+    /// the line that we failed to parse
+    pub line: String,
+    pub section: ParseSection,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to parse {:?} section of object {}", self.section, self.object_index)?;
+        if let Some(oid) = &self.original_oid {
+            write!(f, " ({})", oid)?;
+        }
+        write!(f, ": '{}'", self.line)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl std::convert::From<ParseError> for io::Error {
+    fn from(orig: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, orig)
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct StructuredCommit {
     pub commit_ref: String,
@@ -81,10 +130,27 @@ pub struct StructuredBlob {
     pub data: Vec<u8>,
 }
 
+/// an annotated tag. lightweight tags are just resets
+/// so they are not represented by this type.
+#[derive(Debug, Default)]
+pub struct StructuredTag {
+    /// the name of the tag without the refs/tags/ prefix
+    pub tag_name: String,
+    pub mark: Option<String>,
+    /// the commit-ish that this tag points to
+    pub from: String,
+    pub original_oid: Option<String>,
+    // we pass --fake-missing-tagger to fast-export, but
+    // a stream from somewhere else might not have a tagger
+    pub tagger: Option<CommitPersonOwned>,
+    pub tag_message: String,
+}
+
 #[derive(Debug)]
 pub enum StructuredObjectType {
     Blob(StructuredBlob),
     Commit(StructuredCommit),
+    Tag(StructuredTag),
     NoType,
 }
 
@@ -103,8 +169,9 @@ pub struct StructuredExportObject {
     // if we see the keyword 'feature', we assume its "feature done"
     pub has_feature_done: bool,
 
-    pub data_size: String,
-
+    // there is no data size here on purpose. the size of the
+    // data section is always the length of the message or blob data
+    // when the object is written, so the object can be modified freely.
     pub object_type: StructuredObjectType,
 }
 
@@ -113,6 +180,7 @@ pub enum BeforeDataParserMode {
     Reset,
     Commit,
     Blob,
+    Tag,
 }
 use BeforeDataParserMode::*;
 
@@ -127,6 +195,8 @@ pub enum NextWordType {
     Oid,
     Mark,
     CommitRef,
+    TagName,
+    TagFrom,
     ResetFrom,
     ResetLine,
     Data,
@@ -137,20 +207,19 @@ use NextWordType::*;
 
 /// here we diverge from git-fast-import spec a bit.
 /// the fast-import spec has several commands, but we only handle
-/// two of them: commit and blob.
-/// we dont handle tags because we ignore tags. resets are part
+/// three of them: commit, blob, and tag.
+/// resets are part
 /// of the before_data_object so we dont treat it as a seperate object,
-/// same goes for feature done. we ignore progress, checkpoint and alias, and the rest
-#[derive(Debug)]
+/// same goes for feature done. a reset with a from is not followed by
+/// an object, so it is on its own, and has NoType. we ignore progress, checkpoint and alias, and the rest
+#[derive(Debug, Default)]
 pub enum ObjectType<'a> {
     Commit(CommitObject<'a>),
     Blob(BlobObject<'a>),
-}
-
-impl<'a> Default for ObjectType<'a> {
-    fn default() -> Self {
-        ObjectType::Commit(CommitObject::default())
-    }
+    Tag(TagObject<'a>),
+    /// a reset with a from has no object after it
+    #[default]
+    NoType,
 }
 
 #[derive(Default, Debug)]
@@ -160,13 +229,25 @@ pub struct CommitPerson<'a> {
     pub timestr: &'a str,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct CommitPersonOwned {
     pub name: Option<String>,
     pub email: String,
     pub timestr: String,
 }
 
+impl CommitPersonOwned {
+    /// the parsed timestr. returns None if the
+    /// timestr is not in the `<seconds> <+/-HHMM>` format
+    pub fn time(&self) -> Option<GitTime> {
+        GitTime::parse(&self.timestr)
+    }
+
+    pub fn set_time(&mut self, time: GitTime) {
+        self.timestr = time.to_string();
+    }
+}
+
 impl<'a> Into<CommitPersonOwned> for &CommitPerson<'a> {
     fn into(self) -> CommitPersonOwned {
         CommitPersonOwned {
@@ -203,6 +284,15 @@ pub struct BlobObject<'a> {
     oid: &'a str,
 }
 
+#[derive(Debug, Default)]
+pub struct TagObject<'a> {
+    name: &'a str,
+    mark: Option<&'a str>,
+    from: &'a str,
+    oid: Option<&'a str>,
+    tagger: Option<CommitPerson<'a>>,
+}
+
 #[derive(Default, Debug)]
 pub struct CommitObject<'a> {
     refname: &'a str,
@@ -215,6 +305,18 @@ pub struct CommitObject<'a> {
     committer: CommitPerson<'a>,
 }
 
+impl<'a> BeforeDataObject<'a> {
+    pub fn original_oid(&self) -> Option<&'a str> {
+        let oid = match &self.object {
+            ObjectType::Commit(c) => c.oid,
+            ObjectType::Blob(b) => b.oid,
+            ObjectType::Tag(t) => t.oid.unwrap_or(""),
+            ObjectType::NoType => "",
+        };
+        if oid.is_empty() { None } else { Some(oid) }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct BeforeDataObject<'a> {
     has_reset: Option<&'a str>,
@@ -292,6 +394,14 @@ pub fn set_object_property<'a>(
         } else if let Mark = next_word_type {
             blob_obj.mark = Some(value);
         }
+    } else if let ObjectType::Tag(tag_obj) = &mut object.object {
+        if let Oid = next_word_type {
+            tag_obj.oid = Some(value);
+        } else if let Mark = next_word_type {
+            tag_obj.mark = Some(value);
+        } else if let TagFrom = next_word_type {
+            tag_obj.from = value;
+        }
     }
 }
 
@@ -307,13 +417,23 @@ pub fn parse_next_word<'a>(
 ) -> Option<()> {
     let next_word = word_split.next()?;
     match next_word_type {
-        Oid | Mark => set_object_property(next_word, object, next_word_type),
+        Oid | Mark | TagFrom => set_object_property(next_word, object, next_word_type),
         CommitRef => {
-            let mut commit_obj = CommitObject::default();
-            commit_obj.refname = next_word;
+            let commit_obj = CommitObject {
+                refname: next_word,
+                ..Default::default()
+            };
             object.object = ObjectType::Commit(commit_obj);
             *parse_mode = BeforeDataParserMode::Commit;
         },
+        TagName => {
+            let tag_obj = TagObject {
+                name: next_word,
+                ..Default::default()
+            };
+            object.object = ObjectType::Tag(tag_obj);
+            *parse_mode = BeforeDataParserMode::Tag;
+        },
         ResetFrom => {
             object.has_reset_from = Some(next_word);
             *parse_mode = BeforeDataParserMode::Initial;
@@ -325,7 +445,7 @@ pub fn parse_next_word<'a>(
             // if we need to handle this, then wed modify the BeforeDataObject
             // to have a Vec<ResetInfo>
             if object.has_reset.is_some() {
-                panic!("This object already has a reset?");
+                return None;
             }
             object.has_reset = Some(next_word);
             *parse_mode = BeforeDataParserMode::Reset;
@@ -365,21 +485,26 @@ pub fn parse_next_word2<'a>(
     Some(())
 }
 
-pub fn parse_author_or_committer_line<'a>(
-    line: &'a str,
-    object: &mut BeforeDataObject<'a>,
-    is_author: bool,
-) -> Option<()> {
+/// parses an author, committer, or tagger line
+pub fn parse_person_line<'a>(line: &'a str) -> Option<CommitPerson<'a>> {
     let captures = get_regex_authorline(line)?;
     let name = captures.get(1)?.as_str();
     let email = captures.get(2)?.as_str();
     let timestr = captures.get(3)?.as_str();
 
-    let person = CommitPerson {
+    Some(CommitPerson {
         name: if name.is_empty() { None } else { Some(name) },
         email,
         timestr,
-    };
+    })
+}
+
+pub fn parse_author_or_committer_line<'a>(
+    line: &'a str,
+    object: &mut BeforeDataObject<'a>,
+    is_author: bool,
+) -> Option<()> {
+    let person = parse_person_line(line)?;
     if let ObjectType::Commit(commit_obj) = &mut object.object {
         if is_author {
             commit_obj.author = Some(person);
@@ -391,12 +516,24 @@ pub fn parse_author_or_committer_line<'a>(
     Some(())
 }
 
+pub fn parse_tagger_line<'a>(
+    line: &'a str,
+    object: &mut BeforeDataObject<'a>,
+) -> Option<()> {
+    let person = parse_person_line(line)?;
+    if let ObjectType::Tag(tag_obj) = &mut object.object {
+        tag_obj.tagger = Some(person);
+    }
+
+    Some(())
+}
+
 pub fn parse_filemodify_line<'a>(
     line: &'a str,
     object: &mut AfterDataObject<'a>,
     parse_mode: &mut AfterDataParserMode,
 ) -> Option<()> {
-    let captures = get_regex_filemodifyline(line).unwrap();
+    let captures = get_regex_filemodifyline(line)?;
     let mode = captures.get(1)?.as_str();
     let dataref = captures.get(2)?.as_str();
     let path = captures.get(3)?.as_str();
@@ -491,11 +628,12 @@ pub fn parse_before_data_line<'a>(
             "feature" => object.has_feature_done = true,
             "reset" => parse_next_word(&mut word_split, object, ResetLine, parse_mode)?,
             "commit" => parse_next_word(&mut word_split, object, CommitRef, parse_mode)?,
+            "tag" => parse_next_word(&mut word_split, object, TagName, parse_mode)?,
             "blob" => {
                 object.object = ObjectType::Blob(BlobObject::default());
                 *parse_mode = Blob;
             }
-            _ => panic!("Unknown initial parsing?\n{}", line),
+            _ => return None,
         },
 
         // if we are not in initial parsing mode, then we are parsing
@@ -507,7 +645,8 @@ pub fn parse_before_data_line<'a>(
         Reset => match first_word {
             "from" => parse_next_word(&mut word_split, object, ResetFrom, parse_mode)?,
             "commit" => parse_next_word(&mut word_split, object, CommitRef, parse_mode)?,
-            _ => panic!("Unknown reset parsing?\n{}", line),
+            "tag" => parse_next_word(&mut word_split, object, TagName, parse_mode)?,
+            _ => return None,
         },
 
         // commit has a lot of stuff to parse out
@@ -519,14 +658,23 @@ pub fn parse_before_data_line<'a>(
             // I dont think we need to handle this because we do --reencode=yes
             "encoding" => (),
             "data" => parse_next_word(&mut word_split, object, Data, parse_mode)?,
-            _ => panic!("Unknown commit parsing?\n{}", line),
+            _ => return None,
         },
 
         Blob => match first_word {
             "mark" => parse_next_word(&mut word_split, object, Mark, parse_mode)?,
             "original-oid" => parse_next_word(&mut word_split, object, Oid, parse_mode)?,
             "data" => parse_next_word(&mut word_split, object, Data, parse_mode)?,
-            _ => panic!("Unknown blob parsing?\n{}", line),
+            _ => return None,
+        },
+
+        Tag => match first_word {
+            "mark" => parse_next_word(&mut word_split, object, Mark, parse_mode)?,
+            "from" => parse_next_word(&mut word_split, object, TagFrom, parse_mode)?,
+            "original-oid" => parse_next_word(&mut word_split, object, Oid, parse_mode)?,
+            "tagger" => parse_tagger_line(line, object)?,
+            "data" => parse_next_word(&mut word_split, object, Data, parse_mode)?,
+            _ => return None,
         }
     }
 
@@ -554,7 +702,7 @@ pub fn parse_after_data_line<'a>(
                 object.fileops.push(FileOps::FileDeleteAll);
                 *parse_mode = AfterMerge;
             }
-            _ => panic!("Unknown after data parsing?\n{}", line),
+            _ => return None,
         },
         // if we have already seen a 'from' keyword
         // then that cannot appear again, so we dont
@@ -570,7 +718,7 @@ pub fn parse_after_data_line<'a>(
                 object.fileops.push(FileOps::FileDeleteAll);
                 *parse_mode = AfterMerge;
             }
-            _ => panic!("Unknown after data parsing?\n{}", line),
+            _ => return None,
         },
 
         // if we have gotten past merge, then we only need to look at potential fileops
@@ -584,41 +732,65 @@ pub fn parse_after_data_line<'a>(
                 object.fileops.push(FileOps::FileDeleteAll);
                 *parse_mode = AfterMerge;
             }
-            _ => panic!("Unknown after data parsing?\n{}", line),
+            _ => return None,
         },
     }
 
     Some(())
 }
 
-pub fn parse_before_data<'a>(before_data_str: &'a String) -> Option<BeforeDataObject<'a>> {
+/// the object_index of the error is always 0 because we dont know
+/// it here. `parse_into_structured_object` fills it in.
+pub fn parse_before_data<'a>(before_data_str: &'a String) -> Result<BeforeDataObject<'a>, ParseError> {
     let mut parser_mode = BeforeDataParserMode::Initial;
     let mut output_obj = BeforeDataObject::default();
     for line in before_data_str.lines() {
         if line.is_empty() { continue; }
-        parse_before_data_line(line, &mut parser_mode, &mut output_obj)?;
+        if parse_before_data_line(line, &mut parser_mode, &mut output_obj).is_none() {
+            return Err(ParseError {
+                object_index: 0,
+                original_oid: owned_string_option(output_obj.original_oid()),
+                line: line.into(),
+                section: ParseSection::BeforeData,
+            });
+        }
     }
 
-    Some(output_obj)
+    Ok(output_obj)
 }
 
-pub fn parse_after_data<'a>(after_data_str: &'a String) -> Option<AfterDataObject<'a>> {
+/// the object_index and original_oid of the error are not known here.
+/// `parse_into_structured_object` fills them in.
+pub fn parse_after_data<'a>(after_data_str: &'a String) -> Result<AfterDataObject<'a>, ParseError> {
     let mut parser_mode = AfterDataParserMode::Initial;
     let mut output_obj = AfterDataObject::default();
 
     for line in after_data_str.lines() {
         if line.is_empty() { continue; }
-        parse_after_data_line(line, &mut parser_mode, &mut output_obj)?;
+        if parse_after_data_line(line, &mut parser_mode, &mut output_obj).is_none() {
+            return Err(ParseError {
+                object_index: 0,
+                original_oid: None,
+                line: line.into(),
+                section: ParseSection::AfterData,
+            });
+        }
     }
 
-    Some(output_obj)
+    Ok(output_obj)
 }
 
-pub fn parse_into_structured_object(unparsed: UnparsedFastExportObject) -> StructuredExportObject {
+pub fn parse_into_structured_object(unparsed: UnparsedFastExportObject) -> Result<StructuredExportObject, ParseError> {
     // print!("{}", unparsed.before_data_str);
     // print!("{}", unparsed.after_data_str);
-    let before_data_obj = parse_before_data(&unparsed.before_data_str).expect("Failed to parse before data section");
-    let after_data_obj = parse_after_data(&unparsed.after_data_str).expect("Failed to parse after data section");
+    let before_data_obj = parse_before_data(&unparsed.before_data_str)
+        .map_err(|mut e| { e.object_index = unparsed.index; e })?;
+    let after_data_obj = parse_after_data(&unparsed.after_data_str)
+        .map_err(|mut e| {
+            e.object_index = unparsed.index;
+            e.original_oid = owned_string_option(before_data_obj.original_oid());
+            e
+        })?;
     
     // println!("---------------------");
     // println!("{:?}", before_data_obj);
@@ -629,7 +801,6 @@ pub fn parse_into_structured_object(unparsed: UnparsedFastExportObject) -> Struc
     output_object.has_feature_done = before_data_obj.has_feature_done;
     output_object.has_reset = owned_string_option(before_data_obj.has_reset);
     output_object.has_reset_from = owned_string_option(before_data_obj.has_reset_from);
-    output_object.data_size = before_data_obj.data.into();
 
     let object_type = match &before_data_obj.object {
         ObjectType::Commit(commit_obj) => {
@@ -665,13 +836,25 @@ pub fn parse_into_structured_object(unparsed: UnparsedFastExportObject) -> Struc
             };
             StructuredObjectType::Blob(structured_blob)
         }
+        ObjectType::Tag(tag_obj) => {
+            let structured_tag = StructuredTag {
+                tag_name: tag_obj.name.into(),
+                mark: owned_string_option(tag_obj.mark),
+                from: tag_obj.from.into(),
+                original_oid: owned_string_option(tag_obj.oid),
+                tagger: tag_obj.tagger.as_ref().map(|t| t.into()),
+                tag_message: String::from_utf8_lossy(&unparsed.data).into(),
+            };
+            StructuredObjectType::Tag(structured_tag)
+        }
+        ObjectType::NoType => StructuredObjectType::NoType,
     };
 
     output_object.object_type = object_type;
 
     // println!("{:#?}", output_object);
 
-    output_object
+    Ok(output_object)
 }
 
 
@@ -705,6 +888,58 @@ data 12"#;
         assert_eq!(obj.author.unwrap().timestr, "1548162866 -0800");
     }
 
+    #[test]
+    fn before_data_object_works_for_tags() {
+        let test_str = r#"
+tag v1.0
+from :2
+original-oid 3cd0a701c18016a30537b556916fa044a63df27e
+tagger Bryan Bryan <bb@email.com> 1548162866 -0800
+data 8"#;
+
+        let test_string = String::from(test_str);
+        let before_obj = parse_before_data(&test_string).unwrap();
+        assert_eq!(before_obj.data, "8");
+        let obj = if let ObjectType::Tag(t) = before_obj.object {
+            t
+        } else { panic!("expected tag object") };
+        assert_eq!(obj.name, "v1.0");
+        assert_eq!(obj.from, ":2");
+        assert_eq!(obj.oid, Some("3cd0a701c18016a30537b556916fa044a63df27e"));
+        let tagger = obj.tagger.unwrap();
+        assert_eq!(tagger.name, Some("Bryan Bryan"));
+        assert_eq!(tagger.timestr, "1548162866 -0800");
+    }
+
+    #[test]
+    fn unknown_lines_return_parse_errors() {
+        let before = String::from("commit refs/heads/master\nmark :1\noriginal-oid abc\nsomething-new 123\ndata 3\n");
+        let unparsed = UnparsedFastExportObject {
+            index: 4,
+            before_data_str: before,
+            data: b"hi\n".to_vec(),
+            after_data_str: String::new(),
+        };
+        let err = parse_into_structured_object(unparsed).unwrap_err();
+        assert_eq!(err.object_index, 4);
+        assert_eq!(err.original_oid, Some("abc".into()));
+        assert_eq!(err.line, "something-new 123");
+        assert_eq!(err.section, ParseSection::BeforeData);
+
+        let before = String::from("commit refs/heads/master\nmark :1\noriginal-oid abc\ndata 3\n");
+        let unparsed = UnparsedFastExportObject {
+            index: 7,
+            before_data_str: before,
+            data: b"hi\n".to_vec(),
+            after_data_str: "from :0\nX unknown fileop\n".into(),
+        };
+        let err = parse_into_structured_object(unparsed).unwrap_err();
+        assert_eq!(err.object_index, 7);
+        assert_eq!(err.original_oid, Some("abc".into()));
+        assert_eq!(err.line, "X unknown fileop");
+        assert_eq!(err.section, ParseSection::AfterData);
+    }
+
     #[test]
     fn regex_author_capture_works() {
         let sample1 = "author Bryan Bryan <bb@email.com> 1548162866 -0800";
@@ -737,4 +972,22 @@ data 12"#;
         let captures = get_regex_filemodifyline(sample1).unwrap();
         assert_eq!(captures.get(1).unwrap().as_str(), "100644");
     }
+
+    #[test]
+    fn regex_filerename_handles_quoted_paths() {
+        let sample1 = "R src/a.txt src/b.txt";
+        let captures = get_regex_filerenameline(sample1).unwrap();
+        assert_eq!(captures.get(1).unwrap().as_str(), "src/a.txt");
+        assert_eq!(captures.get(2).unwrap().as_str(), "src/b.txt");
+
+        let sample2 = r#"R "my folder/a \"b\".txt" "other folder/c.txt""#;
+        let captures = get_regex_filerenameline(sample2).unwrap();
+        assert_eq!(captures.get(1).unwrap().as_str(), r#""my folder/a \"b\".txt""#);
+        assert_eq!(captures.get(2).unwrap().as_str(), "\"other folder/c.txt\"");
+
+        let sample3 = "C \"my folder/a.txt\" b.txt";
+        let captures = get_regex_filecopyline(sample3).unwrap();
+        assert_eq!(captures.get(1).unwrap().as_str(), "\"my folder/a.txt\"");
+        assert_eq!(captures.get(2).unwrap().as_str(), "b.txt");
+    }
 }
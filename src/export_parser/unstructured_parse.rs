@@ -1,5 +1,6 @@
-use std::io::{BufReader, Error, ErrorKind, BufRead, Read};
-use std::{path::Path, process::Stdio};
+use std::io::{BufReader, Error, ErrorKind, BufRead};
+use std::{path::{Path, PathBuf}, process::Stdio};
+use super::structured_parse::{ParseError, ParseSection};
 
 pub enum ParseState {
     BeforeData,
@@ -8,6 +9,8 @@ pub enum ParseState {
 }
 
 pub struct UnparsedFastExportObject {
+    /// the position of this object in the stream, starting at 0
+    pub index: usize,
     pub before_data_str: String,
     pub data: Vec<u8>,
     pub after_data_str: String,
@@ -20,6 +23,63 @@ pub fn make_stdio_err(message: &str) -> Error {
     Error::new(kind, message)
 }
 
+/// if the data size is not a number, we dont know how much to read,
+/// so the rest of the stream cannot be parsed.
+pub fn make_data_size_err(index: usize, before_data_str: &str, line: &str) -> Error {
+    let original_oid = before_data_str.lines()
+        .find(|l| l.starts_with("original-oid "))
+        .map(|l| l["original-oid ".len()..].to_string());
+    let err = ParseError {
+        object_index: index,
+        original_oid,
+        line: line.into(),
+        section: ParseSection::Data,
+    };
+    err.into()
+}
+
+/// the lines outside of the data sections are kept as strings, so if one
+/// of them is not valid utf8, we fail instead of replacing the bytes
+/// that are not valid, which would silently rewrite things like paths and names.
+pub fn make_invalid_utf8_err(index: usize, section: ParseSection, line_vec: &[u8]) -> Error {
+    let err = ParseError {
+        object_index: index,
+        original_oid: None,
+        line: format!("{} (not valid utf8)", String::from_utf8_lossy(line_vec)),
+        section,
+    };
+    err.into()
+}
+
+/// read the next line without its newline. returns None at the end of the stream.
+fn read_line<R: BufRead>(
+    bufreader: &mut R,
+    index: usize,
+    section: ParseSection,
+) -> Result<Option<String>, Error> {
+    let mut line_vec = vec![];
+    let num_read = bufreader.read_until(b'\n', &mut line_vec)?;
+    if num_read == 0 {
+        return Ok(None);
+    }
+    if line_vec.last() == Some(&b'\n') {
+        line_vec.pop();
+    }
+    match String::from_utf8(line_vec) {
+        Ok(line) => Ok(Some(line)),
+        Err(e) => Err(make_invalid_utf8_err(index, section, e.as_bytes())),
+    }
+}
+
+/// objects without a data section (a `reset` with a `from`) dont have
+/// anything that tells us how long they are. they are done once they
+/// have their `from`, and then a blank line, a progress line, or
+/// the next object ends them. a `reset` without a `from` (or a `feature`)
+/// is not done, since it goes together with the object after it.
+fn is_complete_without_data(before_data_str: &str) -> bool {
+    before_data_str.lines().any(|l| l.starts_with("from "))
+}
+
 pub fn make_expected_progress_string(progress_num: u32) -> String {
     let mut s = String::with_capacity(32);
     s.push_str("progress ");
@@ -28,37 +88,159 @@ pub fn make_expected_progress_string(progress_num: u32) -> String {
     s
 }
 
-// TODO:
-// add a way to allow parsing from a read stream, and not necessarily
-// opening a new git fast-export command.
-// this would be useful to read from a file that contains
-// the fast-export text without running the command
-// repeatedly for every time you want to split.
+/// where to read the fast-export text from.
+pub enum FastExportSource {
+    /// spawn a `git fast-export` command for the given refs.
+    /// see `resolve_export_refs` for what the refs can be.
+    /// optionally specify a path to the git repo if you
+    /// are not currently in it.
+    GitExport {
+        export_refs: Vec<String>,
+        with_blobs: bool,
+        location: Option<PathBuf>,
+    },
+    /// read text that was already exported, eg: a file that contains
+    /// a saved `git fast-export` stream, stdin, or a pipe from another tool.
+    /// this is useful to export once and then split many times
+    /// without running the fast-export command repeatedly.
+    Stream(Box<dyn BufRead + Send>),
+}
+
+impl FastExportSource {
+    pub fn from_stream<R: BufRead + Send + 'static>(reader: R) -> FastExportSource {
+        FastExportSource::Stream(Box::new(reader))
+    }
+
+    pub fn parse_with_callback<O, E>(
+        self,
+        cb: impl FnMut(UnparsedFastExportObject) -> Result<O, E>,
+    ) -> Result<(), Error> {
+        match self {
+            FastExportSource::GitExport { export_refs, with_blobs, location } => {
+                parse_git_filter_export_refs_with_callback(&export_refs, with_blobs, location, cb)
+            }
+            FastExportSource::Stream(reader) => {
+                parse_fast_export_stream_with_callback(reader, cb)
+            }
+        }
+    }
+}
+
+/// returns true if this line starts a new top level command
+/// in a fast-export stream. used to know when an object has ended
+/// in the case where the stream does not have progress lines after every object.
+pub fn is_start_of_object(line: &str) -> bool {
+    let first_word = line.split_whitespace().next().unwrap_or("");
+    matches!(first_word, "commit" | "blob" | "reset" | "tag" | "feature" | "done")
+}
+
+/// run a git command, and return its stdout if it succeeded
+fn git_output<P: AsRef<Path>>(args: &[&str], repo_location: Option<P>) -> Result<Option<String>, Error> {
+    let child = exechelper::spawn_with_env_ex2(
+        args, &[], &[], repo_location,
+        Some(Stdio::null()), Some(Stdio::null()), Some(Stdio::piped()),
+    )?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into()))
+}
+
+/// the full name of the branch that HEAD points to, eg: `refs/heads/main`.
+/// errors if HEAD is detached.
+pub fn get_default_branch<P: AsRef<Path>>(repo_location: Option<P>) -> Result<String, Error> {
+    match git_output(&["git", "symbolic-ref", "-q", "HEAD"], repo_location)? {
+        Some(branch) if !branch.trim().is_empty() => Ok(branch.trim().to_string()),
+        _ => Err(make_stdio_err("HEAD does not point to a branch, so a branch to export has to be given")),
+    }
+}
+
+/// turn the refs to export into the arguments for git fast-export.
+/// each ref can be:
+/// - a branch or ref name, eg: `main` or `refs/heads/main`
+/// - `--all` to export every ref
+/// - a glob, eg: `refs/heads/release/*`, which is expanded to every
+///   ref that matches it. it is an error if nothing matches.
+/// - a revision range like `v1..main`, or an exclusion like `^v1`
+///   to only export the commits that were added since then.
+///
+/// other than `--all`, refs cant start with a `-`, so that
+/// they cant be mistaken for options to git fast-export.
+///
+/// if there are no refs (other than exclusions), the branch
+/// that HEAD points to is exported.
+pub fn resolve_export_refs<P: AsRef<Path>>(
+    export_refs: &[String],
+    repo_location: Option<P>,
+) -> Result<Vec<String>, Error> {
+    let repo_location = repo_location.map(|l| l.as_ref().to_path_buf());
+    let mut resolved = vec![];
+    if export_refs.iter().all(|r| r.starts_with('^')) {
+        resolved.push(get_default_branch(repo_location.as_ref())?);
+    }
+    for export_ref in export_refs {
+        if export_ref.starts_with('-') && export_ref != "--all" {
+            return Err(make_stdio_err(&format!("Ref {} cant start with a -", export_ref)));
+        }
+        let is_glob = export_ref.contains(['*', '?', '[']);
+        if !is_glob {
+            resolved.push(export_ref.clone());
+            continue;
+        }
+        let args = ["git", "for-each-ref", "--format=%(refname)", export_ref.as_str()];
+        let matched = git_output(&args, repo_location.as_ref())?.unwrap_or_default();
+        let before = resolved.len();
+        resolved.extend(matched.lines().map(|l| l.to_string()));
+        if resolved.len() == before {
+            return Err(make_stdio_err(&format!("No refs match {}", export_ref)));
+        }
+    }
+    Ok(resolved)
+}
+
+/// like `parse_git_filter_export_refs_with_callback`, but for
+/// one branch. if the branch is None, the branch that HEAD points to is used.
+pub fn parse_git_filter_export_with_callback<O, E, P: AsRef<Path>>(
+    export_branch: Option<String>,
+    with_blobs: bool,
+    repo_location: Option<P>,
+    cb: impl FnMut(UnparsedFastExportObject) -> Result<O, E>,
+) -> Result<(), Error>{
+    let export_refs: Vec<String> = export_branch.into_iter().collect();
+    parse_git_filter_export_refs_with_callback(&export_refs, with_blobs, repo_location, cb)
+}
 
 /// This 'parser' will only parse the data section
 /// and put the rest of the info into a 'metadata' string
 /// for future parsing. the rationale is that we need to parse the data section
 /// seperately anyway since we need to know when to resume parsing the other
 /// sections.
+/// every ref is exported in one run of git fast-export, so
+/// commits that are shared between refs are only output once.
+/// see `resolve_export_refs` for what the refs can be.
 /// optionally specify a path to the
 /// git repo if you are not currently in it.
-pub fn parse_git_filter_export_with_callback<O, E, P: AsRef<Path>>(
-    export_branch: Option<String>,
+pub fn parse_git_filter_export_refs_with_callback<O, E, P: AsRef<Path>>(
+    export_refs: &[String],
     with_blobs: bool,
     repo_location: Option<P>,
     cb: impl FnMut(UnparsedFastExportObject) -> Result<O, E>,
 ) -> Result<(), Error>{
     // let now = Instant::now();
-    let export_branch = export_branch.unwrap_or("master".into());
+    let repo_location = repo_location.map(|l| l.as_ref().to_path_buf());
+    let export_refs = resolve_export_refs(export_refs, repo_location.as_ref())?;
     let mut fast_export_command = vec!["git", "fast-export", "--show-original-ids",
-        "--signed-tags=strip", "--tag-of-filtered-object=drop",
+        "--signed-tags=strip", "--tag-of-filtered-object=rewrite",
         "--fake-missing-tagger","--reference-excluded-parents",
-        "--reencode=yes", "--use-done-feature", &export_branch,
+        "--reencode=yes", "--use-done-feature",
         "--progress", "1"
     ];
     if !with_blobs {
         fast_export_command.push("--no-data");
     }
+    // resolve_export_refs already rejected any refs that look like options
+    fast_export_command.extend(export_refs.iter().map(|r| r.as_str()));
 
     let mut child = exechelper::spawn_with_env_ex2(
         &fast_export_command, &[], &[], repo_location,
@@ -70,13 +252,28 @@ pub fn parse_git_filter_export_with_callback<O, E, P: AsRef<Path>>(
         None => return Err(make_stdio_err("failed to take child.stdout")),
     };
 
+    let bufreader = BufReader::new(child_stdout);
+    let res = parse_fast_export_stream_with_callback(bufreader, cb);
+    if res.is_err() {
+        let _ = child.kill();
+    }
+    // eprintln!("Spent {:?} on reading the git stream", now.elapsed());
+    res
+}
+
+/// same as `parse_git_filter_export_with_callback` but instead of
+/// spawning git fast-export, read the fast-export text from any
+/// buffered reader. The stream does not need to contain progress lines,
+/// but if it does, they are used to know when an object ends.
+pub fn parse_fast_export_stream_with_callback<O, E, R: BufRead>(
+    reader: R,
+    cb: impl FnMut(UnparsedFastExportObject) -> Result<O, E>,
+) -> Result<(), Error> {
     let mut cb = cb;
+    let mut bufreader = reader;
     let mut parse_state = ParseState::BeforeData;
-    let mut expected_object = 1;
-    let mut expected_progress_string = make_expected_progress_string(expected_object);
-    let mut bufreader = BufReader::new(child_stdout);
-    // let mut bufreader = BufReader::new(child_stdout).lines();
-    
+    let mut index = 0;
+
     let mut before_data_str = String::new();
     let mut data_vec: Vec<u8> = vec![];
     let mut after_data_str = String::new();
@@ -84,15 +281,54 @@ pub fn parse_git_filter_export_with_callback<O, E, P: AsRef<Path>>(
     loop {
         match parse_state {
             ParseState::BeforeData => {
-                let mut line_vec = vec![];
-                let num_read = bufreader.read_until('\n' as u8, &mut line_vec)?;
-                if num_read == 0 { break; }
-                line_vec.pop(); // remove trailing slash
-                let line = String::from_utf8_lossy(&line_vec[..]);
-                if line.starts_with("data ") {
-                    let data_size_index = 5; // data + space is 5 chars
-                    let data_size = line.get(data_size_index..).unwrap();
-                    let data_size: usize = data_size.parse().unwrap();
+                let line = match read_line(&mut bufreader, index, ParseSection::BeforeData)? {
+                    Some(line) => line,
+                    None => {
+                        // a data-less object at the end of the stream
+                        // still has to be passed along
+                        if !before_data_str.trim().is_empty() {
+                            let unparsed_obj = UnparsedFastExportObject {
+                                index, before_data_str, data: data_vec, after_data_str
+                            };
+                            if cb(unparsed_obj).is_err() {
+                                return Err(make_stdio_err("Error from callback, closing fast-export stream"));
+                            }
+                        }
+                        break;
+                    }
+                };
+                let is_progress = line.starts_with("progress ");
+                let ends_current = if is_progress || line == "done" {
+                    !before_data_str.trim().is_empty()
+                } else if line.is_empty() || is_start_of_object(&line) {
+                    is_complete_without_data(&before_data_str)
+                } else {
+                    false
+                };
+                if ends_current {
+                    let unparsed_obj = UnparsedFastExportObject {
+                        index, before_data_str, data: data_vec, after_data_str
+                    };
+                    if cb(unparsed_obj).is_err() {
+                        return Err(make_stdio_err("Error from callback, closing fast-export stream"));
+                    }
+                    index += 1;
+                    before_data_str = String::new();
+                    data_vec = vec![];
+                    after_data_str = String::new();
+                }
+                // fast-import stops reading at done, so we do too
+                if line == "done" {
+                    break;
+                }
+                if is_progress || (line.is_empty() && before_data_str.is_empty()) {
+                    continue;
+                }
+                if let Some(data_size) = line.strip_prefix("data ") {
+                    let data_size: usize = match data_size.parse() {
+                        Ok(n) => n,
+                        Err(_) => return Err(make_data_size_err(index, &before_data_str, &line)),
+                    };
                     parse_state = ParseState::Data(data_size);
                 }
                 before_data_str.push_str(&line);
@@ -108,33 +344,45 @@ pub fn parse_git_filter_export_with_callback<O, E, P: AsRef<Path>>(
                 data_vec = temp_vec;
             }
             ParseState::AfterData => {
-                let mut line_vec = vec![];
-                let num_read = bufreader.read_until('\n' as u8, &mut line_vec)?;
-                if num_read == 0 { break; }
-                line_vec.pop(); // remove trailing slash
-                let line = unsafe { String::from_utf8_unchecked(line_vec) };
-                if line.starts_with(&expected_progress_string) {
-                    expected_object += 1;
-                    expected_progress_string = make_expected_progress_string(expected_object);
-
-                    let unparsed_obj = UnparsedFastExportObject {
-                        before_data_str, data: data_vec, after_data_str
-                    };
-                    match cb(unparsed_obj) {
-                        Ok(_) => {},
-                        Err(_) => { // TODO: add bound on E that it should be debug?
-                            let _ = child.kill();
+                let line = read_line(&mut bufreader, index, ParseSection::AfterData)?;
+                let line = match line {
+                    Some(line) => line,
+                    None => {
+                        // the stream ended right after this object, so
+                        // we still have to pass it along
+                        let unparsed_obj = UnparsedFastExportObject {
+                            index, before_data_str, data: data_vec, after_data_str
+                        };
+                        if cb(unparsed_obj).is_err() {
                             return Err(make_stdio_err("Error from callback, closing fast-export stream"));
                         }
+                        break;
+                    }
+                };
+                let is_progress = line.starts_with("progress ");
+                if is_progress || is_start_of_object(&line) {
+                    let unparsed_obj = UnparsedFastExportObject {
+                        index, before_data_str, data: data_vec, after_data_str
+                    };
+                    if cb(unparsed_obj).is_err() {
+                        // TODO: add bound on E that it should be debug?
+                        return Err(make_stdio_err("Error from callback, closing fast-export stream"));
                     }
 
-                    // TODO: handle error from callback
-                    // and close stream then return io error ourselves
-
+                    index += 1;
                     before_data_str = String::new();
                     data_vec = vec![];
                     after_data_str = String::new();
                     parse_state = ParseState::BeforeData;
+                    if line == "done" {
+                        break;
+                    }
+                    // if this stream doesnt have progress lines, then the line
+                    // we just read is the start of the next object
+                    if !is_progress {
+                        before_data_str.push_str(&line);
+                        before_data_str.push('\n');
+                    }
                 } else {
                     after_data_str.push_str(&line);
                     after_data_str.push('\n');
@@ -143,6 +391,5 @@ pub fn parse_git_filter_export_with_callback<O, E, P: AsRef<Path>>(
         }
     }
 
-    // eprintln!("Spent {:?} on reading the git stream", now.elapsed());
     Ok(())
 }
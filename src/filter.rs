@@ -1,26 +1,176 @@
 use super::export_parser;
-use export_parser::{StructuredExportObject, StructuredCommit};
+use export_parser::{StructuredExportObject, StructuredCommit, StructuredTag, CommitPersonOwned};
 use export_parser::FileOpsOwned;
+use export_parser::FastExportSource;
 use super::filter_state::FilterState;
+use super::filter_pattern;
+use super::blob_store::{PendingBlobs, DEFAULT_BLOB_MEMORY_LIMIT};
+use super::replace_text::ReplaceText;
+use super::mailmap::Mailmap;
+use super::commit_map;
+use super::commit_hashes;
 use std::io::Write;
+use std::io::BufRead;
+use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
 use std::process::Stdio;
 use std::{path::{PathBuf, Path}, io};
 use io::Error;
 
+/// how a `PathPattern` decides if it matches a path
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PathMatchMode {
+    /// raw `starts_with`. `lib` matches `lib/a.rs`, but also
+    /// `library/a.rs` and `lib.rs`
+    #[default]
+    Prefix,
+    /// `lib` (or `lib/`) matches the path `lib` itself, or anything
+    /// inside the `lib/` directory, but not `library/a.rs` or `lib.rs`
+    Directory,
+    /// `lib.rs` only matches the file `lib.rs`
+    ExactFile,
+}
+
+impl FromStr for PathMatchMode {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefix" => Ok(PathMatchMode::Prefix),
+            "dir" | "directory" => Ok(PathMatchMode::Directory),
+            "exact" | "file" => Ok(PathMatchMode::ExactFile),
+            _ => Err(FilterError(format!("Unknown path match mode: {}. Expected one of: prefix, dir, exact", s))),
+        }
+    }
+}
+
+/// a path, and how to match it. converting from a string
+/// uses `PathMatchMode::Prefix`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathPattern {
+    pub path: String,
+    pub mode: PathMatchMode,
+}
+
+impl PathPattern {
+    pub fn new<S: Into<String>>(path: S, mode: PathMatchMode) -> PathPattern {
+        PathPattern { path: path.into(), mode }
+    }
+
+    pub fn directory<S: Into<String>>(path: S) -> PathPattern {
+        PathPattern::new(path, PathMatchMode::Directory)
+    }
+
+    pub fn exact_file<S: Into<String>>(path: S) -> PathPattern {
+        PathPattern::new(path, PathMatchMode::ExactFile)
+    }
+
+    fn dir_name(&self) -> &str {
+        self.path.trim_end_matches('/')
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self.mode {
+            PathMatchMode::Prefix => path.starts_with(&self.path),
+            PathMatchMode::ExactFile => path == self.path,
+            PathMatchMode::Directory => {
+                let dir = self.dir_name();
+                if dir.is_empty() {
+                    // the root directory contains everything
+                    return true;
+                }
+                path.starts_with(dir) && (
+                    path.len() == dir.len() ||
+                    path[dir.len()..].starts_with('/')
+                )
+            }
+        }
+    }
+
+    /// returns the path with the part that we matched replaced with dest.
+    /// only valid to call if `self.matches(path)`
+    pub fn rename(&self, path: &str, dest: &str) -> String {
+        match self.mode {
+            PathMatchMode::Prefix => path.replacen(&self.path, dest, 1),
+            PathMatchMode::ExactFile => dest.to_string(),
+            PathMatchMode::Directory => {
+                let rest = &path[self.dir_name().len()..];
+                let dest_dir = dest.trim_end_matches('/');
+                if dest_dir.is_empty() {
+                    // moving a directory to the root
+                    rest.trim_start_matches('/').to_string()
+                } else {
+                    format!("{}{}", dest_dir, rest)
+                }
+            }
+        }
+    }
+}
+
+impl From<&str> for PathPattern {
+    fn from(orig: &str) -> Self {
+        PathPattern::new(orig, PathMatchMode::Prefix)
+    }
+}
+
+impl From<String> for PathPattern {
+    fn from(orig: String) -> Self {
+        PathPattern::new(orig, PathMatchMode::Prefix)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum FilterRule {
-    FilterRulePathInclude(String),
-    FilterRulePathExclude(String),
-    FilterRulePathRename(String, String),
+    FilterRulePathInclude(PathPattern),
+    FilterRulePathExclude(PathPattern),
+    FilterRulePathRename(PathPattern, String),
+    /// include paths that match a gitignore style glob, eg: `**/*.proto`
+    FilterRulePathIncludeGlob(String),
+    /// exclude paths that match a gitignore style glob, eg: `docs/**/*.png`
+    FilterRulePathExcludeGlob(String),
+    /// include paths where the regex matches anywhere in the path
+    FilterRulePathIncludeRegex(String),
+    /// exclude paths where the regex matches anywhere in the path
+    FilterRulePathExcludeRegex(String),
+    /// (regex, replacement). the first match of the regex in the path
+    /// is replaced, and the replacement can use capture groups like `$1`.
+    /// eg: `services/(\w+)/api/` -> `api/$1/`
+    FilterRulePathRenameRegex(String, String),
+    /// only keep what is inside of this directory, and make
+    /// it the new root. paths outside of this directory are always
+    /// excluded, even if a rule before or after it includes them.
+    /// like git-filter-repo's `--subdirectory-filter`
+    FilterRuleSubdirectoryFilter(String),
+    /// keep everything, but move it all inside of this directory.
+    /// this is applied after any other renames.
+    /// like git-filter-repo's `--to-subdirectory-filter`
+    FilterRuleToSubdirectoryFilter(String),
+    /// only keep commits where the regex matches the author,
+    /// which is matched as `Name <email>`
+    FilterRuleAuthorInclude(String),
+    /// drop commits where the regex matches the author,
+    /// which is matched as `Name <email>`
+    FilterRuleAuthorExclude(String),
+    /// like `FilterRuleAuthorInclude`, but for the committer
+    FilterRuleCommitterInclude(String),
+    /// like `FilterRuleAuthorExclude`, but for the committer
+    FilterRuleCommitterExclude(String),
+    /// only keep commits that were committed before
+    /// this time (seconds since the epoch)
+    FilterRuleCommittedBefore(i64),
+    /// only keep commits that were committed at or after
+    /// this time (seconds since the epoch)
+    FilterRuleCommittedAfter(i64),
 }
 pub use FilterRule::*;
 
 pub type FilterRules = Vec<FilterRule>;
-pub struct FilterError(String);
+#[derive(Debug)]
+pub struct FilterError(pub(crate) String);
 
 impl From<FilterError> for io::Error {
     fn from(orig: FilterError) -> Self {
-        io::Error::new(io::ErrorKind::Other, orig.0)
+        io::Error::other(orig.0)
     }
 }
 
@@ -47,10 +197,7 @@ pub enum FilterAsReset {
 
 impl FilterResponse {
     pub fn is_used(&self) -> bool {
-        match self {
-            FilterResponse::DontUse => false,
-            _ => true,
-        }
+        !matches!(self, FilterResponse::DontUse)
     }
 
     pub fn is_a_reset(self) -> Option<FilterAsReset> {
@@ -61,6 +208,32 @@ impl FilterResponse {
     }
 }
 
+/// when to prune commits. like git-filter-repo's
+/// `--prune-empty` and `--prune-degenerate`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PruneEmpty {
+    /// prune them, even if they were like that in the original history
+    Always,
+    /// only prune them if they became like that because of filtering
+    #[default]
+    Auto,
+    /// never prune them
+    Never,
+}
+
+impl FromStr for PruneEmpty {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(PruneEmpty::Always),
+            "auto" => Ok(PruneEmpty::Auto),
+            "never" => Ok(PruneEmpty::Never),
+            _ => Err(FilterError(format!("Unknown prune mode: {}. Expected one of: always, auto, never", s))),
+        }
+    }
+}
+
 /// Filter options are
 /// just the initial options passed to initiate
 /// the filtering operation. the actual
@@ -69,10 +242,68 @@ impl FilterResponse {
 #[derive(Debug, Default)]
 pub struct FilterOptions<T: Write> {
     pub stream: T,
-    /// defaults to master
+    /// the branch to filter. if neither this nor refs are given,
+    /// the branch that HEAD points to is filtered
     pub branch: Option<String>,
+    /// more refs to filter in the same run, so that the commits they
+    /// share are only filtered once. these can be ref names, `--all`,
+    /// or globs like `refs/heads/release/*`.
+    /// see `export_parser::resolve_export_refs`
+    pub refs: Vec<String>,
+    /// commits reachable from these revisions are not filtered. eg:
+    /// filtering `main` and excluding the commit from the last sync
+    /// only filters what was added since then. the parents of the
+    /// first new commits are then raw oids, which get rewired
+    /// with the previous_commit_map
+    pub exclude_revs: Vec<String>,
+    /// old oid -> new oid of the commits that an earlier run rewrote,
+    /// eg: from its `commit-map`. see `commit_map::read_commit_map`.
+    /// a commit that maps to the zero oid was pruned, and it is an
+    /// error to use it as a parent, unless `commit_map::add_pruned_ref_tips`
+    /// found its nearest kept ancestor
+    pub previous_commit_map: HashMap<String, String>,
     pub default_include: bool,
     pub with_blobs: bool,
+    /// when filtering with rules, blobs are held until a kept
+    /// commit uses them. this is the max number of bytes of held blobs
+    /// to keep in memory. blobs past this limit are written to a temp file.
+    /// defaults to `blob_store::DEFAULT_BLOB_MEMORY_LIMIT`.
+    /// None means there is no limit, so a history with a lot of
+    /// blobs that are never used can run out of memory
+    pub blob_memory_limit: Option<usize>,
+    /// when filtering with rules, remove every blob bigger than
+    /// this many bytes, and every file modification that uses it.
+    /// this requires the blob data, so it implies with_blobs. when
+    /// filtering a stream, it is an error if the stream doesnt have it
+    pub strip_blobs_bigger_than: Option<usize>,
+    /// when filtering with rules, remove the blobs with these
+    /// original oids, and every file modification that uses them.
+    pub strip_blobs_with_ids: HashSet<String>,
+    /// replacements to apply to the data of every blob
+    /// before it is filtered. this requires the blob data,
+    /// so it implies with_blobs
+    pub replace_text: Option<ReplaceText>,
+    /// replacements to apply to every commit and tag message
+    /// before it is filtered.
+    pub replace_message: Option<ReplaceText>,
+    /// rewrite the names and emails of every author,
+    /// committer, and tagger before they are filtered.
+    pub mailmap: Option<Mailmap>,
+    /// when filtering with rules, what to do with commits
+    /// that have no file changes. defaults to auto
+    pub prune_empty: PruneEmpty,
+    /// what to do with merge commits that become degenerate.
+    /// see `rewire_commit_parents`. defaults to auto
+    pub prune_degenerate: PruneEmpty,
+    /// when filtering directly into git fast-import, write a
+    /// git-filter-repo compatible `commit-map` and `ref-map`
+    /// into this directory afterwards. see `commit_map`
+    pub write_maps_to: Option<PathBuf>,
+    /// rewrite the hashes of earlier commits in commit messages
+    /// (eg: "reverts abc1234") to the new hashes of those commits.
+    /// the new hashes are learned from git fast-import, so
+    /// this only works with `filter_with_rules_direct`
+    pub rewrite_commit_hashes: bool,
     // TODO:
     // pub num_threads: Option<usize>,
 }
@@ -82,10 +313,203 @@ impl<T: Write> From<T> for FilterOptions<T> {
         FilterOptions {
             stream: orig,
             branch: None,
+            refs: vec![],
+            exclude_revs: vec![],
+            previous_commit_map: HashMap::new(),
             default_include: false,
             with_blobs: false,
+            blob_memory_limit: Some(DEFAULT_BLOB_MEMORY_LIMIT),
+            strip_blobs_bigger_than: None,
+            strip_blobs_with_ids: HashSet::new(),
+            replace_text: None,
+            replace_message: None,
+            mailmap: None,
+            prune_empty: PruneEmpty::Auto,
+            prune_degenerate: PruneEmpty::Auto,
+            write_maps_to: None,
+            rewrite_commit_hashes: false,
+        }
+    }
+}
+
+/// parse a size like `500`, `10K`, `5M`, or `1G` into a number of bytes.
+/// like git-filter-repo, the suffixes are powers of 1024.
+pub fn parse_size(size: &str) -> Result<usize, FilterError> {
+    let size = size.trim();
+    let (number, multiplier) = match size.chars().last() {
+        Some('k') | Some('K') => (&size[..size.len() - 1], 1024),
+        Some('m') | Some('M') => (&size[..size.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    number.parse::<usize>().ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| FilterError(format!("Invalid size: {}. Expected a number optionally followed by K, M, or G", size)))
+}
+
+/// git-filter-repo regex renames use python style `\1` to reference
+/// capture groups, but our regex crate uses `${1}`. we accept both.
+pub fn convert_python_replacement(replacement: &str) -> String {
+    let mut out = String::with_capacity(replacement.len());
+    let chars: Vec<char> = replacement.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '\\' && i + 1 < chars.len() && chars[i + 1].is_ascii_digit() {
+            out.push_str("${");
+            out.push(chars[i + 1]);
+            out.push('}');
+            i += 2;
+            continue;
+        }
+        out.push(chars[i]);
+        i += 1;
+    }
+    out
+}
+
+/// parse filter rules from text in the same format as
+/// git-filter-repo's `--paths-from-file`. one rule per line:
+/// - `path/to/thing` (or `literal:path/to/thing`) includes a file, or a directory
+///   and everything inside of it
+/// - `glob:**/*.proto` includes paths that match the glob
+/// - `regex:^src/.*\.rs$` includes paths that match the regex
+/// - `literal:old==>new` and `regex:old==>new` rename paths.
+///
+/// blank lines and lines starting with `#` are ignored.
+pub fn parse_filter_rules_from_str(text: &str) -> Result<FilterRules, FilterError> {
+    let mut filter_rules = vec![];
+    for (line_index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let make_err = |reason: &str| {
+            FilterError(format!("Invalid rule on line {}: {}\n{}", line_index + 1, reason, line))
+        };
+        let (kind, rule) = if let Some(rule) = line.strip_prefix("literal:") {
+            ("literal", rule)
+        } else if let Some(rule) = line.strip_prefix("glob:") {
+            ("glob", rule)
+        } else if let Some(rule) = line.strip_prefix("regex:") {
+            ("regex", rule)
+        } else {
+            ("literal", line)
+        };
+
+        let mut rename_split = rule.splitn(2, "==>");
+        let src = rename_split.next().unwrap_or("");
+        let dest = rename_split.next();
+        if src.is_empty() {
+            return Err(make_err("missing path"));
+        }
+        let filter_rule = match (kind, dest) {
+            ("literal", None) => FilterRulePathInclude(PathPattern::directory(src)),
+            ("literal", Some(dest)) => FilterRulePathRename(PathPattern::directory(src), dest.into()),
+            ("glob", None) => FilterRulePathIncludeGlob(src.into()),
+            ("glob", Some(_)) => return Err(make_err("glob renames are not supported")),
+            ("regex", None) => FilterRulePathIncludeRegex(src.into()),
+            (_, Some(dest)) => FilterRulePathRenameRegex(src.into(), convert_python_replacement(dest)),
+            _ => return Err(make_err("unknown rule")),
+        };
+        filter_rules.push(filter_rule);
+    }
+
+    validate_filter_rules(&filter_rules)?;
+    Ok(filter_rules)
+}
+
+/// read a file with the same format as
+/// git-filter-repo's `--paths-from-file`.
+/// see `parse_filter_rules_from_str`
+pub fn load_filter_rules_from_file<P: AsRef<Path>>(path: P) -> io::Result<FilterRules> {
+    let text = std::fs::read_to_string(path)?;
+    let filter_rules = parse_filter_rules_from_str(&text)?;
+    Ok(filter_rules)
+}
+
+/// parse blob oids to strip, one per line. they have to be full
+/// oids (sha1 or sha256), since fast-export never abbreviates them.
+/// empty lines, and lines starting with `#` are ignored.
+pub fn parse_blob_ids_from_str(text: &str) -> Result<HashSet<String>, FilterError> {
+    let mut blob_ids = HashSet::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !line.chars().all(|c| c.is_ascii_hexdigit()) {
+            let err_str = format!("Invalid blob id: {}", line);
+            return Err(FilterError(err_str));
+        }
+        if line.len() != 40 && line.len() != 64 {
+            let err_str = format!("Invalid blob id: {}. Blob ids cannot be abbreviated", line);
+            return Err(FilterError(err_str));
+        }
+        blob_ids.insert(line.to_ascii_lowercase());
+    }
+    Ok(blob_ids)
+}
+
+/// read a file of blob oids to strip.
+/// see `parse_blob_ids_from_str`
+pub fn load_blob_ids_from_file<P: AsRef<Path>>(path: P) -> io::Result<HashSet<String>> {
+    let text = std::fs::read_to_string(path)?;
+    let blob_ids = parse_blob_ids_from_str(&text)?;
+    Ok(blob_ids)
+}
+
+/// a subdirectory for the subdirectory filters must be a
+/// relative path to a directory, eg: `lib/core` or `lib/core/`
+pub fn validate_subdirectory(dir: &str) -> Result<(), FilterError> {
+    let make_err = |reason: &str| {
+        FilterError(format!("Invalid subdirectory '{}': {}", dir, reason))
+    };
+    if dir.starts_with('/') {
+        return Err(make_err("must be relative to the root of the repository"));
+    }
+    let trimmed = dir.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return Err(make_err("must not be empty"));
+    }
+    if trimmed.starts_with('"') || trimmed.contains('\n') {
+        return Err(make_err("should not be quoted"));
+    }
+    for component in trimmed.split('/') {
+        match component {
+            "" => return Err(make_err("must not contain empty path components")),
+            "." | ".." => return Err(make_err("must not contain . or .. path components")),
+            _ => {}
         }
     }
+    Ok(())
+}
+
+/// check that every pattern in the filter rules is valid before
+/// we start filtering. otherwise an invalid pattern would
+/// just silently never match.
+pub fn validate_filter_rules(filter_rules: &FilterRules) -> Result<(), FilterError> {
+    for filter_rule in filter_rules {
+        match filter_rule {
+            FilterRulePathIncludeGlob(glob) | FilterRulePathExcludeGlob(glob)
+                if !filter_pattern::is_valid_glob(glob) => {
+                return Err(FilterError(format!("Invalid glob pattern: {}", glob)));
+            }
+            FilterRuleSubdirectoryFilter(dir) | FilterRuleToSubdirectoryFilter(dir) => {
+                validate_subdirectory(dir)?;
+            }
+            FilterRulePathIncludeRegex(regex_str) |
+            FilterRulePathExcludeRegex(regex_str) |
+            FilterRulePathRenameRegex(regex_str, _) |
+            FilterRuleAuthorInclude(regex_str) |
+            FilterRuleAuthorExclude(regex_str) |
+            FilterRuleCommitterInclude(regex_str) |
+            FilterRuleCommitterExclude(regex_str)
+                if !filter_pattern::is_valid_regex(regex_str) => {
+                return Err(FilterError(format!("Invalid regex pattern: {}", regex_str)));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
 }
 
 // TODO: originally i had seperate functions for
@@ -109,16 +533,18 @@ pub fn should_use_file(
     };
     let mut should_keep = default_include;
     let mut replace = None;
+    let mut move_under = None;
+    let mut outside_subdirectory = false;
     for filter_rule in filter_rules {
         match filter_rule {
             FilterRulePathInclude(include) => {
-                if check_path.starts_with(include) {
+                if include.matches(check_path) {
                     should_keep = true;
                 }
             }
             FilterRulePathExclude(exclude) => {
-                if check_path.starts_with(exclude) {
-                    if check_path == exclude {
+                if exclude.matches(check_path) {
+                    if check_path == exclude.path {
                         // if it matches exactly, we should not iterate anymore
                         // this is a definitive exclude
                         return false;
@@ -127,13 +553,59 @@ pub fn should_use_file(
                 }
             }
             FilterRulePathRename(src, dest) => {
-                if check_path.starts_with(src) {
-                    replace = Some(check_path.replacen(src, dest, 1));
+                if src.matches(check_path) {
+                    replace = Some(src.rename(check_path, dest));
+                    should_keep = true;
+                }
+            }
+            FilterRulePathIncludeGlob(glob) => {
+                if filter_pattern::glob_matches(glob, check_path) {
+                    should_keep = true;
+                }
+            }
+            FilterRulePathExcludeGlob(glob) => {
+                if filter_pattern::glob_matches(glob, check_path) {
+                    should_keep = false;
+                }
+            }
+            FilterRulePathIncludeRegex(regex_str) => {
+                if filter_pattern::regex_matches(regex_str, check_path) {
+                    should_keep = true;
+                }
+            }
+            FilterRulePathExcludeRegex(regex_str) => {
+                if filter_pattern::regex_matches(regex_str, check_path) {
+                    should_keep = false;
+                }
+            }
+            FilterRulePathRenameRegex(regex_str, replacement) => {
+                if let Some(replaced) = filter_pattern::regex_replace(regex_str, check_path, replacement) {
+                    replace = Some(replaced);
+                    should_keep = true;
+                }
+            }
+            FilterRuleSubdirectoryFilter(dir) => {
+                let dir_pattern = PathPattern::directory(dir.as_str());
+                if dir_pattern.matches(check_path) {
+                    replace = Some(dir_pattern.rename(check_path, ""));
                     should_keep = true;
+                } else {
+                    outside_subdirectory = true;
                 }
             }
+            FilterRuleToSubdirectoryFilter(dir) => {
+                move_under = Some(dir.trim_end_matches('/'));
+                should_keep = true;
+            }
+            // these are for whole commits. see should_use_commit
+            FilterRuleAuthorInclude(_) | FilterRuleAuthorExclude(_) |
+            FilterRuleCommitterInclude(_) | FilterRuleCommitterExclude(_) |
+            FilterRuleCommittedBefore(_) | FilterRuleCommittedAfter(_) => {}
         }
     }
+    if outside_subdirectory {
+        return false;
+    }
 
     // we want to handle path replace after everything else.
     // consider the case:
@@ -141,17 +613,24 @@ pub fn should_use_file(
     // FilterPathRename (src/ -> lib/)
     // then say, we are deciding if we want to include src/a.txt
     // since the path rename comes 
-    if let Some(replace_with) = replace {        
-        if should_keep {
-            *path = replace_with;
+    if should_keep {
+        let mut new_path = match replace {
+            Some(replace_with) => replace_with,
+            None => check_path.to_string(),
+        };
+        if let Some(dir) = move_under {
+            new_path = format!("{}/{}", dir, new_path);
         }
-    }
-    // if git fast-export sees a path that has a space, it wraps it in quotes
-    // but for our pattern matching above, it would be easier if it didnt have spaces
-    // so after we filter, if we still want to keep this, and it still has
-    // spaces, then we have to readd quotes to the ends of the path
-    if should_keep && re_add_quotes && path.contains(' ') {
-        *path = format!("\"{}\"", path);
+        // if git fast-export sees a path that has a space, it wraps it in quotes
+        // but for our pattern matching above, it would be easier if it didnt have spaces
+        // so after we filter, if we still want to keep this, and it has
+        // spaces (or escape sequences from the original), then we have to
+        // add quotes to the ends of the path. note that a rename can
+        // add spaces to a path that was not originally quoted.
+        if new_path.contains(' ') || (re_add_quotes && new_path.contains('\\')) {
+            new_path = format!("\"{}\"", new_path);
+        }
+        *path = new_path;
     }
 
     should_keep
@@ -159,332 +638,487 @@ pub fn should_use_file(
 
 pub fn apply_filter_rules_to_fileops(
     default_include: bool,
-    _filter_state: &mut FilterState,
+    filter_state: &mut FilterState,
     commit: &mut StructuredCommit,
     filter_rules: &FilterRules,
 ) -> Vec<FileOpsOwned> {
     let mut newfileops = vec![];
+    let track_files = rules_need_file_state(filter_rules, default_include) ||
+        !filter_state.stripped_blobs.is_empty();
+    if track_files {
+        filter_state.start_commit_files(commit.from.as_deref(), &commit.merges);
+    }
     for op in commit.fileops.drain(..) {
+        // the state of the files before this op is applied. we need
+        // it for renames/copies where the source is something we exclude.
+        let src_info = match &op {
+            FileOpsOwned::FileRename(src, _) | FileOpsOwned::FileCopy(src, _) => {
+                filter_state.get_file(src).cloned()
+            }
+            _ => None,
+        };
+        // a delete is tracked after it was filtered, see below
+        if track_files && !matches!(op, FileOpsOwned::FileDelete(_)) {
+            filter_state.track_fileop(&op);
+        }
+        // a rename/copy of a file whose blob was stripped
+        // has nothing to move, since the file was never written
+        let src_is_stripped = match &src_info {
+            Some((_, dataref)) => filter_state.is_stripped_blob(dataref),
+            None => false,
+        };
         match op {
-            // TODO: not sure if need to handle these?
-            // by not doing anything here, we are explicitly
-            // removing them, but file copy
-            // is a hard one, not sure what to do for that one
-            // is removing that ok?
-            FileOpsOwned::FileCopy(_, _) => {}
-            FileOpsOwned::FileDeleteAll => {}
-            FileOpsOwned::NoteModify(_, _) => {}
-
-            // renames are tricky too, but I think
-            // we want to drop them unless
-            // we are renaming something WITHIN the
-            // include path. ie: both src and dest
-            // must contain the path we want to include.
-            // otherwise, if we want to include B, and
-            // the rename is from A -> B, then why would we include
-            // something about A here since we are filtering it out?
-            FileOpsOwned::FileRename(_, _) => {
-                // TODO:
-                // if src.starts_with(include_path) && dest.starts_with(include_path) {
-                //     newfileops.push(FileOpsOwned::FileRename(src, dest));
-                // }
+            // deleteall clears everything, including the files we keep,
+            // and notes are not paths, so keep both of these as is.
+            FileOpsOwned::FileDeleteAll => newfileops.push(FileOpsOwned::FileDeleteAll),
+            FileOpsOwned::NoteModify(dataref, commitish) => {
+                newfileops.push(FileOpsOwned::NoteModify(dataref, commitish));
+            }
+
+            // for renames and copies, the src and dest are checked
+            // seperately:
+            // - if both are kept, the op is kept with both paths rewritten
+            // - if only the src is kept, then for a rename, the file
+            //   left our kept set, so it becomes a delete of the src.
+            //   for a copy, nothing changed in our kept set, so drop it.
+            // - if only the dest is kept, the file entered our kept set,
+            //   so it becomes a modify of the dest using the blob of the src.
+            FileOpsOwned::FileRename(mut src, mut dest) => {
+                let keep_src = should_use_file(&mut src, filter_rules, default_include);
+                let keep_dest = should_use_file(&mut dest, filter_rules, default_include);
+                match (keep_src, keep_dest) {
+                    (true, true) if src_is_stripped => {
+                        filter_state.stripped_paths.insert(dest);
+                    }
+                    (true, true) => newfileops.push(FileOpsOwned::FileRename(src, dest)),
+                    (true, false) => newfileops.push(FileOpsOwned::FileDelete(src)),
+                    (false, true) => {
+                        // if we dont know what the src was, theres
+                        // nothing we can put at the dest, so it gets dropped
+                        if let Some((mode, dataref)) = src_info {
+                            push_modify_unless_stripped(filter_state, &mut newfileops, mode, dataref, dest);
+                        }
+                    }
+                    (false, false) => {}
+                }
+            }
+            FileOpsOwned::FileCopy(mut src, mut dest) => {
+                let keep_src = should_use_file(&mut src, filter_rules, default_include);
+                let keep_dest = should_use_file(&mut dest, filter_rules, default_include);
+                match (keep_src, keep_dest) {
+                    (true, true) if src_is_stripped => {
+                        filter_state.stripped_paths.insert(dest);
+                    }
+                    (true, true) => newfileops.push(FileOpsOwned::FileCopy(src, dest)),
+                    (false, true) => {
+                        if let Some((mode, dataref)) = src_info {
+                            push_modify_unless_stripped(filter_state, &mut newfileops, mode, dataref, dest);
+                        }
+                    }
+                    _ => {}
+                }
             }
 
             // easiest cases. if it exists, keep it
             FileOpsOwned::FileModify(mode, dataref, mut path) => {
-                if should_use_file(&mut path, filter_rules, default_include) {
-                    newfileops.push(FileOpsOwned::FileModify(mode, dataref, path));
+                // an empty path can happen if a file has the same name
+                // as a directory we moved to the root. theres nowhere to put it.
+                if should_use_file(&mut path, filter_rules, default_include) && !path.is_empty() {
+                    push_modify_unless_stripped(filter_state, &mut newfileops, mode, dataref, path);
                 }
             }
-            FileOpsOwned::FileDelete(mut path) => {
-                if should_use_file(&mut path, filter_rules, default_include) {
-                    newfileops.push(FileOpsOwned::FileDelete(path));
+            FileOpsOwned::FileDelete(path) => {
+                let mut new_path = path.clone();
+                if should_use_file(&mut new_path, filter_rules, default_include) {
+                    // if the directory that we moved to the root was deleted,
+                    // then that means everything was deleted. but if it was a file
+                    // with the same name as that directory, it was never written.
+                    if new_path.is_empty() {
+                        if filter_state.get_file(&path).is_none() {
+                            newfileops.push(FileOpsOwned::FileDeleteAll);
+                        }
+                    } else {
+                        newfileops.push(FileOpsOwned::FileDelete(new_path));
+                    }
+                }
+                if track_files {
+                    filter_state.track_fileop(&FileOpsOwned::FileDelete(path));
                 }
             }
         }
     }
+    if track_files {
+        filter_state.finish_commit_files(commit.mark.as_deref());
+    }
     newfileops
 }
 
+/// the files of a commit are only needed if a rename/copy can end up
+/// with only one of its paths kept, if its blob can be stripped, or if
+/// a path can become empty. if every path is kept (possibly moved
+/// under a directory) then renames and copies can stay as they are.
+fn rules_need_file_state(filter_rules: &FilterRules, default_include: bool) -> bool {
+    !default_include || filter_rules.iter().any(|rule| !matches!(rule,
+        FilterRuleToSubdirectoryFilter(_) |
+        FilterRuleAuthorInclude(_) | FilterRuleAuthorExclude(_) |
+        FilterRuleCommitterInclude(_) | FilterRuleCommitterExclude(_) |
+        FilterRuleCommittedBefore(_) | FilterRuleCommittedAfter(_)
+    ))
+}
+
+fn push_modify_unless_stripped(
+    filter_state: &mut FilterState,
+    newfileops: &mut Vec<FileOpsOwned>,
+    mode: String,
+    dataref: String,
+    path: String,
+) {
+    if filter_state.is_stripped_blob(&dataref) {
+        filter_state.stripped_paths.insert(path);
+    } else {
+        newfileops.push(FileOpsOwned::FileModify(mode, dataref, path));
+    }
+}
+
+fn person_matches(regex_str: &str, person: &CommitPersonOwned) -> bool {
+    let person_str = match &person.name {
+        Some(name) => format!("{} <{}>", name, person.email),
+        None => format!("<{}>", person.email),
+    };
+    filter_pattern::regex_matches(regex_str, &person_str)
+}
+
+/// check the rules that apply to the commit itself rather
+/// than its paths. returns false if any of them say to drop it.
+/// note that the changes of a dropped commit are dropped with it.
+pub fn should_use_commit(commit: &StructuredCommit, filter_rules: &FilterRules) -> bool {
+    // fast-import uses the committer if there is no author
+    let author = commit.get_author().unwrap_or(&commit.committer);
+    let committed_at = commit.committer.time().map(|t| t.seconds);
+    for filter_rule in filter_rules {
+        let keep = match filter_rule {
+            FilterRuleAuthorInclude(regex_str) => person_matches(regex_str, author),
+            FilterRuleAuthorExclude(regex_str) => !person_matches(regex_str, author),
+            FilterRuleCommitterInclude(regex_str) => person_matches(regex_str, &commit.committer),
+            FilterRuleCommitterExclude(regex_str) => !person_matches(regex_str, &commit.committer),
+            // if we cant tell when it was committed, it cant be in the window
+            FilterRuleCommittedBefore(before) => matches!(committed_at, Some(t) if t < *before),
+            FilterRuleCommittedAfter(after) => matches!(committed_at, Some(t) if t >= *after),
+            _ => true,
+        };
+        if !keep {
+            return false;
+        }
+    }
+    true
+}
+
 pub fn perform_filter(
     default_include: bool,
+    prune_empty: PruneEmpty,
+    prune_degenerate: PruneEmpty,
     filter_state: &mut FilterState,
     commit: &mut StructuredCommit,
     filter_rules: &FilterRules,
 ) -> Result<FilterResponse, FilterError> {
+    let was_empty = commit.fileops.is_empty();
     let newfileops = apply_filter_rules_to_fileops(default_include, filter_state, commit, filter_rules);
-    // if we have pruned all of the file operations,
-    // then we dont want to use this object as a commit, but rather
-    // as a reset. Also, make sure to update the mark map with our parent
+    // a merge that still merges something is not empty even without
+    // file changes. whether it gets pruned is up to prune_degenerate,
+    // see rewire_commit_parents
+    let is_still_a_merge = !commit.merges.is_empty() && count_kept_parents(filter_state, commit) > 1;
+    let prune_because_empty = newfileops.is_empty() && !is_still_a_merge && match prune_empty {
+        PruneEmpty::Always => true,
+        // only prune it if it became empty because of our filtering
+        PruneEmpty::Auto => !was_empty,
+        PruneEmpty::Never => false,
+    };
+    // if we have pruned all of the file operations, or the commit
+    // itself should be dropped, then we dont want to use this object
+    // as a commit. Also, make sure to update the mark map with our parent
     // so that if a future commit tries to do:
     // from :THIS
     // then they will instead do:
     // from :THIS_PARENT
-    if newfileops.is_empty() {
-        match (&commit.from, &commit.mark) {
-            (Some(from), Some(mark)) => {
-                let insert_with = match filter_state.mark_map.get(from) {
-                    Some(transitive_parent) => Some(transitive_parent.clone()),
-                    None => None,
-                };
-                match insert_with {
-                    Some(transitive_parent) => {
-                        // eprintln!("A {} -> {}", mark, transitive_parent);
-                        filter_state.mark_map.insert(mark.clone(), transitive_parent.clone());
-                    }
-                    None => {
-                        // eprintln!("B {} -> {}", mark, "");
-                        filter_state.mark_map.insert(mark.clone(), "".into());
-                    }
-                }
-            },
-            (None, Some(mark)) => {
-                // eprintln!("D {:?} -> {:?}", commit.mark, commit.from);
-                filter_state.mark_map.insert(mark.clone(), "".into());
-            },
-            // im not sure these other cases
-            // are possible?
-            _ => {},
+    if prune_because_empty || !should_use_commit(commit, filter_rules) {
+        if let Some(mark) = &commit.mark {
+            filter_state.map_dropped_commit(mark, commit.from.as_deref());
         }
         return Ok(FilterResponse::DontUse);
     }
     commit.fileops = newfileops;
+    rewire_commit_parents(filter_state, commit, prune_degenerate)
+}
 
-    // if this merge doesnt pertain to anything we know about
-    // just skip it, dont bother entering it in
-    if !commit.merges.is_empty() {
-        let has_from = match &commit.from {
-            Some(from) => filter_state.has_nonempty_mark(from),
-            None => false,
-        };
-        // TODO: add a impl fn for the filter state to
-        // test if a mark exists and is non emtpy. basically
-        // dont repeat this code over and over:
-        let has_all_merges = commit.merges.iter()
-            .all(|m| filter_state.has_nonempty_mark(m));
-
-        if !has_from && !has_all_merges {
-            // eprintln!("Dont use because it doesnt has from and it doesnt have merges");
-            return Ok(FilterResponse::DontUse);
-        } else if has_from && !has_all_merges {
-            // if the from exists, but the merges dont, then
-            // remove all merges that dont exist:
-            commit.merges.retain(|merge| filter_state.has_nonempty_mark(merge));
+/// how many different kept commits the parents of this commit map to
+fn count_kept_parents(filter_state: &FilterState, commit: &StructuredCommit) -> usize {
+    let mut kept_parents: Vec<&String> = vec![];
+    for parent in commit.from.iter().chain(commit.merges.iter()) {
+        if let Some(mapto) = filter_state.mark_map.get(parent) {
+            if !mapto.is_empty() && !kept_parents.contains(&mapto) {
+                kept_parents.push(mapto);
+            }
         }
     }
+    kept_parents.len()
+}
 
-    // at this point, we know that we will use this commit, so
-    // it should map to itself. ie: if we are X, and
-    // future commits say
-    // from :X
-    // then we want it to say from :X, and not
-    // from :Parent_of_X
-    if let Some(mark) = &commit.mark {
-        // eprintln!("C {} -> {}", mark, mark);
-        filter_state.mark_map.insert(mark.clone(), mark.clone());
-    }
-
+/// point the from and merges of a commit that we are keeping at
+/// the nearest kept ancestors, according to the filter_state.mark_map.
+/// this can still decide to drop the commit if it was a merge
+/// commit that became degenerate, depending on prune_degenerate.
+/// a merge is degenerate if it has no file changes, and after removing
+/// parents that are ancestors of other parents, it has less than 2 parents.
+pub fn rewire_commit_parents(
+    filter_state: &mut FilterState,
+    commit: &mut StructuredCommit,
+    prune_degenerate: PruneEmpty,
+) -> Result<FilterResponse, FilterError> {
+    let was_merge = !commit.merges.is_empty();
     // if we havent used a commit yet, but this is our first,
-    // then we want this to not have a from line:
+    // then we want this to not have a from line, unless the from
+    // is a commit from an earlier run that we know how to rewire
+    // (or that the earlier run pruned, which is an error below)
     if !filter_state.have_used_a_commit {
-        commit.from = None;
+        let from_is_known = match &commit.from {
+            Some(from) => filter_state.mark_map.contains_key(from) ||
+                filter_state.pruned_previous_commits.contains(from),
+            None => false,
+        };
+        if !from_is_known {
+            commit.from = None;
+        }
     }
+    let original_parents: Vec<String> = commit.from.iter()
+        .chain(commit.merges.iter()).cloned().collect();
+
     // if we are X, and we depend on Z, we should
     // check if Z points to something else.
     // as mentioned above, if Z was filtered out, we have a
-    // filter_state.mark_map that contains some parent of Z
-    let mut from_pruned = false;
-    if let Some(ref mut from) = commit.from {
-        match filter_state.mark_map.get(from) {
-            Some(mapto) => {
-                if mapto.is_empty() {
-                    // this indicates we are trying to point to a from
-                    // that doesnt actually exist. in this case,
-                    // instead of using a from, we want to remove the
-                    // from and any merges
-                    from_pruned = true;
-                } else {
-                    *from = mapto.clone();
-                }
-            }
-            None => {
-                // TODO: remove panic, and return a result instead
+    // filter_state.mark_map that contains some parent of Z.
+    // if it maps to an empty mark, none of Z's ancestors were kept,
+    // so we remove that parent entirely.
+    let mut parents: Vec<String> = vec![];
+    for (i, parent) in original_parents.iter().enumerate() {
+        if filter_state.pruned_previous_commits.contains(parent) {
+            // if we skipped it, this commit would lose the history
+            // that the earlier run kept, or even become a new root
+            let err_str = format!(
+                "{:?} has the parent {}, which the earlier run pruned, so its nearest kept ancestor is not known.\nIf it was the tip of a ref, the ref-map of that run knows it. see commit_map::add_pruned_ref_tips",
+                commit.mark, parent
+            );
+            return Err(FilterError(err_str));
+        }
+        let mapto = match filter_state.mark_map.get(parent) {
+            Some(mapto) => mapto,
+            None if i == 0 && commit.from.is_some() => {
                 let err_str = format!(
-                    "Found a commit that we dont know in the map!\nWe are {:?} -> from {}. failed to find the from",
-                    commit.mark, from
+                    "Found a commit that we dont know in the map!\nWe are {:?} -> from {}. failed to find the from.\nIf it is a parent that was not exported, it needs to be in the previous_commit_map",
+                    commit.mark, parent
                 );
                 return Err(FilterError(err_str));
             }
+            // if this merge doesnt pertain to anything
+            // we know about, just skip it
+            None => continue,
+        };
+        if !mapto.is_empty() && !parents.contains(mapto) {
+            parents.push(mapto.clone());
         }
     }
 
-    // if we have a merge commit, its possible it will become not amerge
-    // commit (ie: we prune parents from N down to 1)
-    // in that case, we want to keep all of their current parents
-    // and then traverse them later to find the one that we
-    // should map to instead
-    let is_merge_commit = commit.from.is_some() && !commit.merges.is_empty();
-    let old_merge_points_to = if is_merge_commit {
-        let mut pointers = vec![];
-        // if the from was pruned, no point in keeping
-        // track of it as a parent...
-        if !from_pruned {
-            if let Some(from) = &commit.from {
-                pointers.push(from.clone());
-            }
-        }
-        // but we do want to potentially keep track
-        // of the merges... these will be checked below
-        for merge in &commit.merges {
-            pointers.push(merge.clone());
-        }
-        Some(pointers)
-    } else { None };
-
-    if from_pruned {
-        commit.from = None;
-        commit.merges = vec![];
-    }
-    // we also want to do the same thing we did above for the from lines
-    // for every single merge. ie: map the original
-    // merge :X
-    // to say:
-    // merge :SOME_PARENT_OF_X
-    for merge in &mut commit.merges {
-        match filter_state.mark_map.get_mut(merge) {
-            Some(mapto) => {
-                *merge = mapto.clone();
-            }
-            None => {}
-        }
-    }
-
-    // we used to be a merge commit, but because of pruning
-    // we became a regular commit. this basically means that
-    // the contents of this commit are the same as one of the
-    // prior commits we are merging, ie: a duplicate.
-    // the decision here is to drop it, but i think optionally,
-    // if you want to allow empty merge commits, maybe have some setting
-    // that keeps this commit with just the message but no changes
-    if is_merge_commit {
-        if commit.merges.is_empty() && commit.from.is_none() {
-            // try to add a statement to the map that says
-            // we now point to one of our parents. we have to iterate
-            // our parents here to find one that still exists.
-            if let Some(pointers) = &old_merge_points_to {
-                if let Some(mark) = &commit.mark {
-                    // try the pointers in order of:
-                    // FROM, MERGE 1, MERGE 2, ...
-                    // and use the first one we find
-                    for pointer_option in pointers {
-                        // eprintln!("We used to point to: {}", &pointer_option);
-                        let pointed_to = match filter_state.mark_map.get(pointer_option) {
-                            Some(pointing_to) => {
-                                if !pointing_to.is_empty() {
-                                    Some(pointing_to.clone())
-                                } else { None }
-                            }
-                            None => None,
-                        };
-                        if let Some(pointing_to) = pointed_to {
-                            filter_state.mark_map.insert(mark.clone(), pointing_to);
-                            return Ok(FilterResponse::DontUse);
-                        }
-                    }
-                }
-            }
-            // regardless if we were able to find a parent to map to,
-            // we still dont want to be used
-            return Ok(FilterResponse::DontUse);
-        }
-    }
-
-    // if its a merge commit, make sure that we dont have
-    // a scenario where:
+    // if a merge now has a parent that is an ancestor of one of its
+    // other parents, then merging it is redundant. ie:
     //   A _
     //   |  \
     //   X  |
     //   Y /
-    // basically if A is a merge of X, and Y,
-    // we make sure that X isnt a direct ancestor of Y
-    // and if so, we prune A, because thats an unnecessary merge commit
-    // it would just be empty.
-    if is_merge_commit {
-        // TODO: handle octopus merges...
-        // right now this will only handle the case where theres a merge
-        // with 2 parents:
-        if let Some(pointers) = &old_merge_points_to {
-            if pointers.len() > 1 {
-                let parent_x = &pointers[0];
-                let parent_y = &pointers[1];
-
-                let x_is_ancestor_of_y = match filter_state.graph.get(parent_x) {
-                    Some(x_parents) => x_parents.contains(parent_y),
-                    None => false,
-                };
-                let y_is_ancestor_of_x = match filter_state.graph.get(parent_y) {
-                    Some(y_parents) => y_parents.contains(parent_x),
-                    None => false,
-                };
-                if x_is_ancestor_of_y || y_is_ancestor_of_x {
-                    if let Some(mark) = &commit.mark {
-                        // eprintln!("NOT USING {} Because its a merge commit whose ancestors ({:?}) are direct parents of each other. Its mark map is: {:?}", mark, pointers, filter_state.mark_map.get(mark));
-                        // remember to update map. we want to then point to the
-                        // most recent child, so we check specifically which case we got:
-                        if x_is_ancestor_of_y {
-                            // in this case we have: THIS_COMMIT -> X -> Y
-                            // so we want to say our mark points to X
-                            filter_state.mark_map.insert(mark.clone(), parent_x.clone());
-                        } else {
-                            // otherwise y is ancestor of x
-                            // in this case we have: THIS_COMMIT -> Y -> X
-                            // so we want to say our mark points to Y
-                            filter_state.mark_map.insert(mark.clone(), parent_y.clone());
-                        }
-                    }
-
-                    return Ok(FilterResponse::DontUse);
-                }
+    // if A is a merge of X, and Y, then Y is not needed.
+    // for auto, we only do this if our filtering changed the parents,
+    // otherwise the merge was already like this in the original history
+    let remove_redundant = was_merge && match prune_degenerate {
+        PruneEmpty::Always => true,
+        PruneEmpty::Auto => parents != original_parents,
+        PruneEmpty::Never => false,
+    };
+    if remove_redundant {
+        let all_parents = parents.clone();
+        parents.retain(|parent| {
+            !all_parents.iter().any(|other| other != parent && filter_state.is_ancestor(parent, other))
+        });
+        if parents.len() < 2 && commit.fileops.is_empty() {
+            // we are a degenerate merge, so dont use this commit, and
+            // point to the parent that is left instead
+            if let Some(mark) = &commit.mark {
+                let mapto = parents.first().cloned().unwrap_or_default();
+                filter_state.mark_map.insert(mark.clone(), mapto);
             }
+            return Ok(FilterResponse::DontUse);
         }
     }
 
-    // update the current graph to say that
-    // we are X, and we have parent(s) Y/Z:
-    match &commit.mark {
-        Some(mark) => {
-            let mut parents = match &commit.from {
-                Some(parent1) => vec![parent1.clone()],
-                None => vec![]
-            };
-            for merge in &commit.merges {
-                parents.push(merge.clone());
-            }
-            filter_state.graph.insert(mark.clone(), parents.clone());
-        }
-        _ => {},
+    // at this point, we know that we will use this commit, so
+    // it should map to itself. ie: if we are X, and
+    // future commits say
+    // from :X
+    // then we want it to say from :X, and not
+    // from :Parent_of_X
+    if let Some(mark) = &commit.mark {
+        filter_state.mark_map.insert(mark.clone(), mark.clone());
+        // update the current graph to say that
+        // we are X, and we have parent(s) Y/Z:
+        filter_state.graph.insert(mark.clone(), parents.clone());
     }
+    let mut parents = parents.into_iter();
+    commit.from = parents.next();
+    commit.merges = parents.collect();
 
     Ok(FilterResponse::UseAsIs)
 }
 
+/// annotated tags point to a commit. if that commit was
+/// pruned, we re-point the tag at the nearest ancestor that was kept.
+/// returns false if the tag should be dropped because
+/// none of the ancestors of its target were kept.
+pub fn perform_tag_filter(
+    filter_state: &FilterState,
+    tag: &mut StructuredTag,
+) -> bool {
+    match filter_state.mark_map.get(&tag.from) {
+        Some(mapto) => {
+            if mapto.is_empty() {
+                return false;
+            }
+            tag.from = mapto.clone();
+            true
+        }
+        // this tag points to something we havent seen,
+        // (eg: a raw sha, or an excluded parent)
+        // so leave it alone
+        None => true,
+    }
+}
+
+/// returns the state after filtering, which includes
+/// things like which paths had their blobs stripped.
 pub fn filter_with_rules<P: AsRef<Path>, T: Write>(
     filter_options: FilterOptions<T>,
     filter_rules: FilterRules,
     location: Option<P>,
-) -> io::Result<()> {
+) -> io::Result<FilterState> {
+    let source = git_export_source(&filter_options, location);
+    filter_source_with_rules(source, filter_options, filter_rules)
+}
+
+/// like `filter_with_rules`, but the fast-export text is read
+/// from the given reader instead of running git fast-export.
+/// the branch and with_blobs options are ignored because
+/// the stream was already exported.
+pub fn filter_stream_with_rules<R: BufRead + Send + 'static, T: Write>(
+    reader: R,
+    filter_options: FilterOptions<T>,
+    filter_rules: FilterRules,
+) -> io::Result<FilterState> {
+    let source = FastExportSource::from_stream(reader);
+    filter_source_with_rules(source, filter_options, filter_rules)
+}
+
+pub fn filter_source_with_rules<T: Write>(
+    source: FastExportSource,
+    filter_options: FilterOptions<T>,
+    filter_rules: FilterRules,
+) -> io::Result<FilterState> {
+    filter_source_with_rules_ex(source, filter_options, filter_rules, None, commit_hashes::SHA1_HASH_LEN)
+}
+
+/// `fast_import_responses` is where git fast-import writes its
+/// responses when the stream is going directly into it.
+/// it is needed to learn the new oids of commits.
+fn filter_source_with_rules_ex<T: Write>(
+    source: FastExportSource,
+    mut filter_options: FilterOptions<T>,
+    filter_rules: FilterRules,
+    mut fast_import_responses: Option<&mut dyn BufRead>,
+    hash_len: usize,
+) -> io::Result<FilterState> {
+    validate_filter_rules(&filter_rules)?;
+    let rewrite_commit_hashes = filter_options.rewrite_commit_hashes;
+    if rewrite_commit_hashes && fast_import_responses.is_none() {
+        return Err(FilterError("Rewriting commit hashes requires filtering directly into git fast-import".into()).into());
+    }
     let mut filter_state = FilterState::default();
+    filter_state.load_previous_commit_map(&filter_options.previous_commit_map);
+    // without the blob data, fileops reference the blob oids directly,
+    // so putting the oids here works for both cases
+    filter_state.stripped_blobs = std::mem::take(&mut filter_options.strip_blobs_with_ids);
+    let mut pending_blobs = PendingBlobs::new(filter_options.blob_memory_limit);
     let default_include = filter_options.default_include;
-    let cb = |obj: &mut StructuredExportObject| -> io::Result<bool> {
-        // TODO: filter on blobs as well:
+    let strip_blobs_bigger_than = filter_options.strip_blobs_bigger_than;
+    let prune_empty = filter_options.prune_empty;
+    let prune_degenerate = filter_options.prune_degenerate;
+    let cb = |obj: &mut StructuredExportObject, stream: &mut T| -> io::Result<bool> {
+        record_original_object(&mut filter_state, obj);
+        perform_reset_filter(&mut filter_state, obj);
         match &mut obj.object_type {
-            export_parser::StructuredObjectType::Blob(_) => Ok(true),
+            export_parser::StructuredObjectType::Blob(_) => {
+                // we dont know yet if any commit we keep will use this blob,
+                // so hold it until one does
+                let blob_obj = std::mem::replace(&mut obj.object_type, export_parser::StructuredObjectType::NoType);
+                if let export_parser::StructuredObjectType::Blob(blob) = blob_obj {
+                    let mark = match &blob.mark {
+                        Some(mark) => mark.clone(),
+                        None => {
+                            obj.object_type = export_parser::StructuredObjectType::Blob(blob);
+                            return Ok(true);
+                        }
+                    };
+                    let too_big = match strip_blobs_bigger_than {
+                        Some(max_size) => blob.data.len() > max_size,
+                        None => false,
+                    };
+                    if too_big {
+                        filter_state.stripped_blobs.insert(mark.clone());
+                    }
+                    filter_state.blob_mark_to_oid.insert(mark.clone(), blob.original_oid.clone());
+                    if !filter_state.is_stripped_blob(&mark) {
+                        pending_blobs.insert(blob)?;
+                    }
+                }
+                // still write anything that came with the blob
+                Ok(obj.has_feature_done || obj.has_reset.is_some())
+            }
             export_parser::StructuredObjectType::Commit(ref mut c) => {
-                let resp = perform_filter(default_include, &mut filter_state, c, &filter_rules)?;
+                if strip_blobs_bigger_than.is_some() {
+                    check_blob_sizes_are_known(c)?;
+                }
+                let resp = perform_filter(
+                    default_include, prune_empty, prune_degenerate,
+                    &mut filter_state, c, &filter_rules,
+                )?;
+                if resp.is_used() {
+                    if let (true, Some(responses)) = (rewrite_commit_hashes, fast_import_responses.as_mut()) {
+                        let mut new_oid_of_mark = |mark: &str| {
+                            commit_hashes::get_mark_from_fast_import(stream, *responses, mark, hash_len)
+                        };
+                        commit_hashes::rewrite_commit_hashes(&mut c.commit_message, &mut filter_state, hash_len, &mut new_oid_of_mark)?;
+                    }
+                    for fileop in &c.fileops {
+                        match fileop {
+                            FileOpsOwned::FileModify(_, dataref, _) |
+                            FileOpsOwned::NoteModify(dataref, _) => {
+                                write_pending_blob(&mut pending_blobs, dataref, stream)?;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
                 if !filter_state.have_used_a_commit && resp.is_used() {
                     filter_state.have_used_a_commit = true;
                 }
                 let is_used = resp.is_used();
+                if let (true, Some(mark)) = (is_used, &c.mark) {
+                    filter_state.last_kept_marks.insert(c.commit_ref.clone(), mark.clone());
+                }
                 if let Some(reset) = resp.is_a_reset() {
                     match reset {
                         FilterAsReset::AsReset(resetref) => {
@@ -497,12 +1131,127 @@ pub fn filter_with_rules<P: AsRef<Path>, T: Write>(
                     }
                     obj.object_type = export_parser::StructuredObjectType::NoType;
                 }
+                if !is_used && obj.has_reset_from.is_some() {
+                    // the reset is for a different ref, so it
+                    // still has to be written without the commit
+                    obj.object_type = export_parser::StructuredObjectType::NoType;
+                    return Ok(true);
+                }
                 Ok(is_used)
             },
+            export_parser::StructuredObjectType::Tag(ref mut t) => {
+                let is_used = perform_tag_filter(&filter_state, t);
+                if is_used {
+                    // tags can point to blobs too
+                    write_pending_blob(&mut pending_blobs, &t.from, stream)?;
+                }
+                Ok(is_used)
+            }
             _ => Ok(true),
         }
     };
-    filter_with_cb(filter_options, location, cb)
+    let stream = filter_objects_with_writer_cb(source, filter_options, cb)?;
+    write_ref_resets_and_done(&filter_state, stream)?;
+    Ok(filter_state)
+}
+
+/// if the tip of a ref was dropped, fast-import leaves it at whatever
+/// kept commit was written to it last (if any), so point it at the
+/// nearest kept ancestor of the original tip instead
+fn write_ref_resets_and_done<T: Write>(filter_state: &FilterState, mut stream: T) -> io::Result<()> {
+    for (reset_ref, from) in filter_state.refs_to_reset() {
+        let obj = StructuredExportObject {
+            has_reset: Some(reset_ref),
+            has_reset_from: Some(from),
+            object_type: export_parser::StructuredObjectType::NoType,
+            ..Default::default()
+        };
+        export_parser::write_to_stream(&mut stream, obj)?;
+    }
+    stream.write_all(b"done\n")
+}
+
+/// without the blob data, fileops reference blobs by their oid
+/// instead of a mark, and we cant know how big those blobs are.
+/// so stripping blobs by size would silently strip nothing.
+fn check_blob_sizes_are_known(commit: &StructuredCommit) -> Result<(), FilterError> {
+    for fileop in &commit.fileops {
+        if let FileOpsOwned::FileModify(_, dataref, path) = fileop {
+            if !dataref.starts_with(':') {
+                return Err(FilterError(format!(
+                    "Cannot strip blobs by size without the blob data, but {} in commit {} uses blob {} which was not in the stream",
+                    path, commit.original_oid, dataref,
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// a `reset` with a `from` can point the ref at a commit
+/// we dropped, so point it at the nearest kept ancestor instead.
+/// if there is none, the reset is removed.
+fn perform_reset_filter(filter_state: &mut FilterState, obj: &mut StructuredExportObject) {
+    let (reset_ref, from) = match (&obj.has_reset, &mut obj.has_reset_from) {
+        (Some(reset_ref), Some(from)) if from.starts_with(':') => (reset_ref, from),
+        _ => return,
+    };
+    match filter_state.mark_map.get(from.as_str()) {
+        Some(mapto) if !mapto.is_empty() => {
+            *from = mapto.clone();
+            filter_state.last_kept_marks.insert(reset_ref.clone(), mapto.clone());
+        }
+        Some(_) => {
+            obj.has_reset = None;
+            obj.has_reset_from = None;
+        }
+        None => {}
+    }
+}
+
+/// remember what we need to know about the original
+/// history before the object gets filtered
+fn record_original_object(filter_state: &mut FilterState, obj: &StructuredExportObject) {
+    if let (Some(reset_ref), Some(from)) = (&obj.has_reset, &obj.has_reset_from) {
+        filter_state.record_reset(reset_ref, from);
+    }
+    match &obj.object_type {
+        export_parser::StructuredObjectType::Commit(commit) => filter_state.record_commit(commit),
+        export_parser::StructuredObjectType::Tag(tag) => filter_state.record_tag(tag),
+        _ => {}
+    }
+}
+
+/// if the dataref is a blob that we are holding, write it now
+/// so that it exists before the object that references it.
+fn write_pending_blob<T: Write>(
+    pending_blobs: &mut PendingBlobs,
+    dataref: &str,
+    stream: &mut T,
+) -> io::Result<()> {
+    if let Some(blob) = pending_blobs.take(dataref)? {
+        let obj = StructuredExportObject {
+            object_type: export_parser::StructuredObjectType::Blob(blob),
+            ..Default::default()
+        };
+        export_parser::write_to_stream(stream, obj)?;
+    }
+    Ok(())
+}
+
+fn git_export_source<P: AsRef<Path>, T: Write>(
+    filter_options: &FilterOptions<T>,
+    location: Option<P>,
+) -> FastExportSource {
+    FastExportSource::GitExport {
+        export_refs: filter_options.branch.iter().chain(filter_options.refs.iter()).cloned()
+            .chain(filter_options.exclude_revs.iter().map(|rev| format!("^{}", rev)))
+            .collect(),
+        with_blobs: filter_options.with_blobs ||
+            filter_options.strip_blobs_bigger_than.is_some() ||
+            filter_options.replace_text.is_some(),
+        location: location.map(|l| l.as_ref().to_path_buf()),
+    }
 }
 
 // temporary function to test out filtering
@@ -511,13 +1260,108 @@ pub fn filter_with_cb<P: AsRef<Path>, T: Write, F: Into<FilterOptions<T>>>(
     location: Option<P>,
     cb: impl FnMut(&mut StructuredExportObject) -> io::Result<bool>,
 ) -> io::Result<()> {
+    let options: FilterOptions<T> = options.into();
+    let source = git_export_source(&options, location);
+    filter_source_with_cb(source, options, cb)
+}
+
+/// like `filter_with_cb`, but the fast-export text is read
+/// from the given reader instead of running git fast-export.
+pub fn filter_stream_with_cb<R: BufRead + Send + 'static, T: Write, F: Into<FilterOptions<T>>>(
+    reader: R,
+    options: F,
+    cb: impl FnMut(&mut StructuredExportObject) -> io::Result<bool>,
+) -> io::Result<()> {
+    let source = FastExportSource::from_stream(reader);
+    filter_source_with_cb(source, options, cb)
+}
+
+/// when the callback returns false for a commit, the commits that
+/// use it as a parent are rewired to its nearest kept ancestor,
+/// and refs whose tip was dropped are reset to it,
+/// the same way as when filtering with rules.
+pub fn filter_source_with_cb<T: Write, F: Into<FilterOptions<T>>>(
+    source: FastExportSource,
+    options: F,
+    cb: impl FnMut(&mut StructuredExportObject) -> io::Result<bool>,
+) -> io::Result<()> {
+    let options: FilterOptions<T> = options.into();
+    let prune_degenerate = options.prune_degenerate;
+    let mut cb = cb;
+    let mut filter_state = FilterState::default();
+    filter_state.load_previous_commit_map(&options.previous_commit_map);
+    let stream = filter_objects_with_writer_cb(source, options, |obj, _| {
+        let is_used = cb(obj)?;
+        // the callback can rename refs, so this is recorded after
+        // it, so that the refs we reset are the ones that get written
+        record_original_object(&mut filter_state, obj);
+        perform_reset_filter(&mut filter_state, obj);
+        match &mut obj.object_type {
+            export_parser::StructuredObjectType::Commit(commit) => {
+                let is_used = is_used && {
+                    rewire_commit_parents(&mut filter_state, commit, prune_degenerate)?.is_used()
+                };
+                if !is_used {
+                    if let Some(mark) = &commit.mark {
+                        if !filter_state.mark_map.contains_key(mark) {
+                            filter_state.map_dropped_commit(mark, commit.from.as_deref());
+                        }
+                    }
+                    if obj.has_reset_from.is_some() {
+                        // the reset is for a different ref, so it
+                        // still has to be written without the commit
+                        obj.object_type = export_parser::StructuredObjectType::NoType;
+                        return Ok(true);
+                    }
+                    return Ok(false);
+                }
+                filter_state.have_used_a_commit = true;
+                if let Some(mark) = &commit.mark {
+                    filter_state.last_kept_marks.insert(commit.commit_ref.clone(), mark.clone());
+                }
+                Ok(true)
+            }
+            export_parser::StructuredObjectType::Tag(tag) if is_used => {
+                Ok(perform_tag_filter(&filter_state, tag))
+            }
+            _ => Ok(is_used),
+        }
+    })?;
+    write_ref_resets_and_done(&filter_state, stream)
+}
+
+/// like `filter_source_with_cb`, but the callback also gets the output
+/// stream so that it can write extra objects before the current one.
+/// this does not rewire the parents of commits. the callback
+/// is responsible for that.
+pub fn filter_source_with_writer_cb<T: Write, F: Into<FilterOptions<T>>>(
+    source: FastExportSource,
+    options: F,
+    cb: impl FnMut(&mut StructuredExportObject, &mut T) -> io::Result<bool>,
+) -> io::Result<()> {
+    let mut stream = filter_objects_with_writer_cb(source, options, cb)?;
+    stream.write_all(b"done\n")?;
+    Ok(())
+}
+
+/// `filter_source_with_writer_cb` without writing the final `done`,
+/// so that the caller can write more to the returned stream first.
+fn filter_objects_with_writer_cb<T: Write, F: Into<FilterOptions<T>>>(
+    source: FastExportSource,
+    options: F,
+    cb: impl FnMut(&mut StructuredExportObject, &mut T) -> io::Result<bool>,
+) -> io::Result<T> {
     let options: FilterOptions<T> = options.into();
     let mut stream = options.stream;
+    let replace_text = options.replace_text;
+    let replace_message = options.replace_message;
+    let mailmap = options.mailmap;
     let mut cb = cb;
-    export_parser::parse_git_filter_export_via_channel(
-        options.branch, options.with_blobs, location,
+    export_parser::parse_fast_export_source_via_channel_ex(
+        source,
         |mut obj| {
-            let succeeded = cb(&mut obj)?;
+            apply_rewrites(&mut obj, &replace_text, &replace_message, &mailmap);
+            let succeeded = cb(&mut obj, &mut stream)?;
             if succeeded {
                 return export_parser::write_to_stream(&mut stream, obj);
             }
@@ -525,51 +1369,132 @@ pub fn filter_with_cb<P: AsRef<Path>, T: Write, F: Into<FilterOptions<T>>>(
         }
     )?;
 
-    stream.write_all(b"done\n")?;
+    Ok(stream)
+}
 
-    Ok(())
+/// the rewriting stage of the pipeline. this runs on every
+/// object before the filter callback sees it.
+fn apply_rewrites(
+    obj: &mut StructuredExportObject,
+    replace_text: &Option<ReplaceText>,
+    replace_message: &Option<ReplaceText>,
+    mailmap: &Option<Mailmap>,
+) {
+    match &mut obj.object_type {
+        export_parser::StructuredObjectType::Blob(blob) => {
+            if let Some(replace_text) = replace_text {
+                replace_text.replace_blob(blob);
+            }
+        }
+        export_parser::StructuredObjectType::Commit(commit) => {
+            if let Some(replace_message) = replace_message {
+                replace_message.replace_message(&mut commit.commit_message);
+            }
+            if let Some(mailmap) = mailmap {
+                mailmap.map_commit(commit);
+            }
+        }
+        export_parser::StructuredObjectType::Tag(tag) => {
+            if let Some(replace_message) = replace_message {
+                replace_message.replace_message(&mut tag.tag_message);
+            }
+            if let Some(mailmap) = mailmap {
+                mailmap.map_tag(tag);
+            }
+        }
+        export_parser::StructuredObjectType::NoType => {}
+    }
 }
 
 pub fn filter_with_rules_direct_ex<P: AsRef<Path>, T: Write>(
     filter_options: FilterOptions<T>,
     filter_rules: FilterRules,
     location: Option<P>,
-) -> io::Result<()> {
-    let exe_and_args = [
-        "git", "-c", "core.ignorecase=false", "fast-import", "--date-format=raw-permissive", "--force", "--quiet"
+) -> io::Result<FilterState> {
+    let mut exe_and_args = vec![
+        "git".to_string(), "-c".into(), "core.ignorecase=false".into(), "fast-import".into(),
+        "--date-format=raw-permissive".into(), "--force".into(), "--quiet".into(),
     ];
+    // fast-import runs in the location, so the marks
+    // file has to be an absolute path
+    let maps_dir = match filter_options.write_maps_to {
+        Some(ref dir) => {
+            std::fs::create_dir_all(dir)?;
+            Some(dir.canonicalize()?)
+        }
+        None => None,
+    };
+    let marks_file = maps_dir.as_ref().map(|dir| dir.join("fast-import-marks"));
+    if let Some(ref marks_file) = marks_file {
+        exe_and_args.push(format!("--export-marks={}", marks_file.display()));
+    }
+    let exe_and_args: Vec<&str> = exe_and_args.iter().map(|s| s.as_str()).collect();
     let location_clone = match location {
         Some(ref l) => Some(l.as_ref().to_owned()),
         None => None,
     };
+    // sha256 repositories have longer hashes to find in the messages
+    let hash_len = if filter_options.rewrite_commit_hashes {
+        commit_hashes::read_hash_len(location_clone.clone())?
+    } else {
+        commit_hashes::SHA1_HASH_LEN
+    };
     let mut gitimport_handle = exechelper::spawn_with_env_ex2(
         &exe_and_args,
         &[], &[],
-        location_clone,
+        location_clone.clone(),
         Some(Stdio::piped()),
         Some(Stdio::null()),
-        Some(Stdio::null())
+        // fast-import writes the responses to get-mark here
+        Some(if filter_options.rewrite_commit_hashes { Stdio::piped() } else { Stdio::null() }),
     )?;
+    let mut gitimport_responses = gitimport_handle.stdout.take().map(io::BufReader::new);
 
     let gitimport_stdin = gitimport_handle.stdin.as_mut().ok_or_else(|| std::io::ErrorKind::InvalidInput)?;
     let overwritten_options = FilterOptions {
         stream: gitimport_stdin,
         branch: filter_options.branch,
+        refs: filter_options.refs,
+        exclude_revs: filter_options.exclude_revs,
+        previous_commit_map: filter_options.previous_commit_map,
         default_include: filter_options.default_include,
         with_blobs: filter_options.with_blobs,
+        blob_memory_limit: filter_options.blob_memory_limit,
+        strip_blobs_bigger_than: filter_options.strip_blobs_bigger_than,
+        strip_blobs_with_ids: filter_options.strip_blobs_with_ids,
+        replace_text: filter_options.replace_text,
+        replace_message: filter_options.replace_message,
+        mailmap: filter_options.mailmap,
+        prune_empty: filter_options.prune_empty,
+        prune_degenerate: filter_options.prune_degenerate,
+        write_maps_to: None,
+        rewrite_commit_hashes: filter_options.rewrite_commit_hashes,
     };
 
-    let res = filter_with_rules(overwritten_options, filter_rules, location);
-    let res2 = gitimport_handle.wait();
-    if res.is_ok() && res2.is_ok() {
-        return Ok(());
+    let source = git_export_source(&overwritten_options, location);
+    let responses = gitimport_responses.as_mut().map(|r| r as &mut dyn BufRead);
+    let res = filter_source_with_rules_ex(source, overwritten_options, filter_rules, responses, hash_len);
+    let status = gitimport_handle.wait();
+    // the marks are only needed to write the maps, so
+    // dont leave them behind, even if something failed
+    let marks = marks_file.map(|marks_file| {
+        let marks = commit_map::read_marks_file(&marks_file);
+        let _ = std::fs::remove_file(&marks_file);
+        marks
+    });
+    let status = status?;
+    let filter_state = res?;
+    // even if the filtering worked, the history was not
+    // rewritten if fast-import did not accept it
+    if !status.success() {
+        return Err(Error::other(format!("git fast-import failed with {}", status)));
     }
-
-    if let Err(e) = res2 {
-        Err(e)
-    } else {
-        res
+    if let (Some(maps_dir), Some(marks)) = (maps_dir, marks) {
+        let marks = marks?;
+        let current_refs = commit_map::read_current_refs(location_clone)?;
+        commit_map::write_maps_to_dir(maps_dir, &filter_state, &marks, &current_refs)?;
     }
+    Ok(filter_state)
 }
 
 /// filter from your given rules and options, and pipe directly
@@ -581,60 +1506,999 @@ pub fn filter_with_rules_direct_ex<P: AsRef<Path>, T: Write>(
 pub fn filter_with_rules_direct<T: Write>(
     filter_options: FilterOptions<T>,
     filter_rules: FilterRules,
-) -> io::Result<()> {
+) -> io::Result<FilterState> {
     let no_location: Option<PathBuf> = None;
     filter_with_rules_direct_ex(filter_options, filter_rules, no_location)
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::io::sink;
-    use std::io::Cursor;
-    use std::{path::PathBuf, io::Read};
-    use export_parser::StructuredObjectType;
-    pub const NO_LOCATION: Option<PathBuf> = None;
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::sink;
+    use std::io::Cursor;
+    use std::{path::PathBuf, io::Read};
+    use export_parser::StructuredObjectType;
+    pub const NO_LOCATION: Option<PathBuf> = None;
+
+    // a fake original oid for test objects, eg: the blob
+    // with mark :2 is bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb2
+    fn test_oid(kind: char, mark: &str) -> String {
+        let id = mark.trim_start_matches(':');
+        format!("{}{}", kind.to_string().repeat(40 - id.len()), id)
+    }
+
+    // a commit to put in a TestStream. by default it is on master,
+    // and authored and committed by agent. its original oid is
+    // made from its mark, eg: :3 is cccccccccccccccccccccccccccccccccccccc3
+    struct TestCommit {
+        branch: String,
+        mark: String,
+        author: String,
+        committer: String,
+        when: i64,
+        message: String,
+        from: Option<String>,
+        merges: Vec<String>,
+        fileops: Vec<String>,
+    }
+
+    fn commit(mark: &str, message: &str) -> TestCommit {
+        TestCommit {
+            branch: "refs/heads/master".into(),
+            mark: mark.into(),
+            author: "agent".into(),
+            committer: "agent".into(),
+            when: 1792312127,
+            message: message.into(),
+            from: None,
+            merges: vec![],
+            fileops: vec![],
+        }
+    }
+
+    impl TestCommit {
+        fn on(mut self, branch: &str) -> Self {
+            self.branch = branch.into();
+            self
+        }
+        fn by(mut self, name: &str) -> Self {
+            self.author = name.into();
+            self.committer = name.into();
+            self
+        }
+        fn committed_by(mut self, name: &str) -> Self {
+            self.committer = name.into();
+            self
+        }
+        fn at(mut self, when: i64) -> Self {
+            self.when = when;
+            self
+        }
+        fn from(mut self, from: &str) -> Self {
+            self.from = Some(from.into());
+            self
+        }
+        fn merge(mut self, merge: &str) -> Self {
+            self.merges.push(merge.into());
+            self
+        }
+        fn op(mut self, fileop: &str) -> Self {
+            self.fileops.push(fileop.into());
+            self
+        }
+    }
+
+    // builds a fast-export stream like the one that
+    // `git fast-export --progress 1 --use-done-feature --show-original-ids`
+    // gives us, so that tests dont have to count data sizes by hand.
+    struct TestStream {
+        text: String,
+        objects: usize,
+    }
+
+    impl TestStream {
+        fn new() -> TestStream {
+            TestStream { text: "feature done\n".into(), objects: 0 }
+        }
+
+        fn object(mut self, text: &str) -> Self {
+            self.objects += 1;
+            self.text.push_str(text);
+            self.text.push_str(&format!("\nprogress {} objects\n", self.objects));
+            self
+        }
+
+        fn blob(self, mark: &str, data: &str) -> Self {
+            self.object(&format!(
+                "blob\nmark {}\noriginal-oid {}\ndata {}\n{}",
+                mark, test_oid('b', mark), data.len(), data,
+            ))
+        }
+
+        // a reset without a from goes right before the commit it resets
+        fn reset(mut self, refname: &str) -> Self {
+            self.text.push_str(&format!("reset {}\n", refname));
+            self
+        }
+
+        fn reset_from(self, refname: &str, from: &str) -> Self {
+            self.object(&format!("reset {}\nfrom {}\n", refname, from))
+        }
+
+        fn commit(self, commit: TestCommit) -> Self {
+            let person = |name: &str| format!("{} <{}@example.com> {} +0000", name, name, commit.when);
+            let mut text = format!(
+                "commit {}\nmark {}\noriginal-oid {}\nauthor {}\ncommitter {}\ndata {}\n{}",
+                commit.branch, commit.mark, test_oid('c', &commit.mark),
+                person(&commit.author), person(&commit.committer),
+                commit.message.len(), commit.message,
+            );
+            if let Some(from) = &commit.from {
+                text.push_str(&format!("from {}\n", from));
+            }
+            for merge in &commit.merges {
+                text.push_str(&format!("merge {}\n", merge));
+            }
+            for fileop in &commit.fileops {
+                text.push_str(&format!("{}\n", fileop));
+            }
+            self.object(&text)
+        }
+
+        fn tag(self, name: &str, from: &str, message: &str) -> Self {
+            let oid = test_oid('a', &self.objects.to_string());
+            self.object(&format!(
+                "tag {}\nfrom {}\noriginal-oid {}\ntagger agent <agent@example.com> 1792312127 +0000\ndata {}\n{}",
+                name, from, oid, message.len(), message,
+            ))
+        }
+
+        fn build(self) -> String {
+            format!("{}done\n", self.text)
+        }
+    }
+
+    #[test]
+    fn filter_path_works() {
+        let writer = sink();
+        filter_with_cb(writer, NO_LOCATION, |obj| {
+            match &obj.object_type {
+                StructuredObjectType::Blob(_) => Ok(true),
+                StructuredObjectType::Commit(commit_obj) => {
+                    if commit_obj.committer.email.contains("jerry") {
+                        Ok(false)
+                    } else {
+                        Ok(true)
+                    }
+                },
+                _ => Ok(true),
+            }
+        }).unwrap();
+    }
+
+    #[test]
+    fn can_modify_filter_objects() {
+        let mut writer = Cursor::new(vec![]);
+        filter_with_cb(&mut writer, NO_LOCATION, |obj| {
+            if let Some(reset) = &mut obj.has_reset {
+                *reset = "refs/heads/NEWBRANCHNAMEAAAAAAA".into();
+            }
+            match &mut obj.object_type {
+                StructuredObjectType::Blob(_) => Ok(true),
+                StructuredObjectType::Commit(commit_obj) => {
+                    commit_obj.commit_ref = commit_obj.commit_ref.replace("master", "NEWBRANCHNAMEAAAAAAA");
+                    Ok(true)
+                },
+                _ => Ok(true),
+            }
+        }).unwrap();
+
+        let mut s = String::from("");
+        writer.set_position(0);
+        writer.read_to_string(&mut s).unwrap();
+        assert!(s.contains("refs/heads/NEWBRANCHNAMEAAAAAAA"));
+        assert!(!s.contains("refs/heads/master"));
+    }
+
+    #[test]
+    fn can_filter_from_a_stream() {
+        let text = TestStream::new()
+            .blob(":1", "a\n")
+            .reset("refs/heads/master")
+            .commit(commit(":2", "one\n").op("M 100644 :1 a.txt"))
+            .commit(commit(":3", "two\n").from(":2").op("M 100644 :1 b.txt"))
+            .build();
+        let reader = Cursor::new(text.to_string());
+        let mut writer = Cursor::new(vec![]);
+        let filter_options: FilterOptions<_> = (&mut writer).into();
+        let filter_rules = vec![FilterRulePathInclude("b.txt".into())];
+        filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
+
+        let mut s = String::from("");
+        writer.set_position(0);
+        writer.read_to_string(&mut s).unwrap();
+        assert!(s.contains("M 100644 :1 b.txt"));
+        assert!(!s.contains("a.txt"));
+        // the first commit only touched a.txt so it should be pruned
+        assert!(!s.contains("mark :2"));
+        assert!(!s.contains("from :2"));
+        assert!(s.ends_with("done\n"));
+    }
+
+    #[test]
+    fn only_blobs_used_by_kept_commits_are_written() {
+        let text = TestStream::new()
+            .blob(":1", "AAAAAA\n")
+            .blob(":2", "BBBBBB\n")
+            .reset("refs/heads/master")
+            .commit(commit(":3", "one\n").op("M 100644 :1 a.txt").op("M 100644 :2 b.txt"))
+            .blob(":4", "CCCCCC\n")
+            .commit(commit(":5", "two\n").from(":3").op("M 100644 :4 a.txt"))
+            .commit(commit(":6", "three\n").from(":5").op("M 100644 :2 c.txt"))
+            .build();
+        // a memory limit of 0 makes every blob get spilled to disk
+        for memory_limit in &[None, Some(0)] {
+            let reader = Cursor::new(text.to_string());
+            let mut writer = Cursor::new(vec![]);
+            let mut filter_options: FilterOptions<_> = (&mut writer).into();
+            filter_options.blob_memory_limit = *memory_limit;
+            let filter_rules = vec![
+                FilterRulePathInclude("b.txt".into()),
+                FilterRulePathInclude("c.txt".into()),
+            ];
+            filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
+
+            let mut s = String::from("");
+            writer.set_position(0);
+            writer.read_to_string(&mut s).unwrap();
+            assert!(s.contains("feature done\n"));
+            assert!(!s.contains("AAAAAA"));
+            assert!(!s.contains("CCCCCC"));
+            // the blob is written once, right before the first commit that uses it
+            assert_eq!(s.matches("BBBBBB").count(), 1);
+            let blob_pos = s.find("mark :2\n").unwrap();
+            let commit_pos = s.find("mark :3\n").unwrap();
+            assert!(blob_pos < commit_pos);
+            assert!(s.contains("M 100644 :2 c.txt"));
+        }
+    }
+
+    #[test]
+    fn big_blobs_get_stripped() {
+        let text = TestStream::new()
+            .blob(":1", "ab\n")
+            .blob(":2", "0123456789\n")
+            .reset("refs/heads/master")
+            .commit(commit(":3", "one\n").op("M 100644 :1 src/a.txt").op("M 100644 :2 build/out.bin"))
+            .commit(commit(":4", "two\n").from(":3").op("R build/out.bin build/out2.bin"))
+            .build();
+        let reader = Cursor::new(text.to_string());
+        let mut writer = Cursor::new(vec![]);
+        let mut filter_options: FilterOptions<_> = (&mut writer).into();
+        filter_options.default_include = true;
+        filter_options.strip_blobs_bigger_than = Some(parse_size("10").unwrap());
+        let filter_rules = vec![FilterRulePathExclude("nothing".into())];
+        let state = filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
+
+        let mut s = String::from("");
+        writer.set_position(0);
+        writer.read_to_string(&mut s).unwrap();
+        assert!(s.contains("M 100644 :1 src/a.txt"));
+        assert!(!s.contains("0123456789"));
+        assert!(!s.contains(":2"));
+        let stripped: Vec<_> = state.stripped_paths.iter().map(|p| p.as_str()).collect();
+        assert_eq!(stripped, vec!["build/out.bin", "build/out2.bin"]);
+        // the second commit only renamed the stripped file, so it is pruned
+        assert!(!s.contains("mark :4"));
+    }
+
+    #[test]
+    fn stripping_blobs_by_size_requires_the_blob_data() {
+        let text = TestStream::new()
+            .reset("refs/heads/master")
+            .commit(commit(":1", "one\n").op("M 100644 78981922613b2afb6025042ff6bd878ac1994e85 a.txt"))
+            .build();
+        let reader = Cursor::new(text.to_string());
+        let mut writer = Cursor::new(vec![]);
+        let mut filter_options: FilterOptions<_> = (&mut writer).into();
+        filter_options.default_include = true;
+        filter_options.strip_blobs_bigger_than = Some(parse_size("10").unwrap());
+        let filter_rules = vec![FilterRulePathExclude("nothing".into())];
+        let err = filter_stream_with_rules(reader, filter_options, filter_rules).unwrap_err();
+        assert!(err.to_string().contains("78981922613b2afb6025042ff6bd878ac1994e85"));
+    }
+
+    #[test]
+    fn blob_ids_must_be_full_oids() {
+        let text = format!("# comment\n\n{}\n{}\n", "A".repeat(40), "b".repeat(64));
+        let blob_ids = parse_blob_ids_from_str(&text).unwrap();
+        assert!(blob_ids.contains(&"a".repeat(40)));
+        assert!(blob_ids.contains(&"b".repeat(64)));
+        assert!(parse_blob_ids_from_str("7898192").is_err());
+        assert!(parse_blob_ids_from_str(&"g".repeat(40)).is_err());
+    }
+
+    #[test]
+    fn blobs_can_be_stripped_by_id() {
+        let secret_oid = test_oid('b', ":2");
+        let with_blobs = TestStream::new()
+            .blob(":1", "ab\n")
+            .blob(":2", "secret\n")
+            .reset("refs/heads/master")
+            .commit(commit(":3", "one\n").op("M 100644 :1 a.txt").op("M 100644 :2 creds.txt"))
+            .build();
+        let without_blobs = TestStream::new()
+            .reset("refs/heads/master")
+            .commit(commit(":3", "one\n")
+                .op(&format!("M 100644 {} a.txt", test_oid('b', ":1")))
+                .op(&format!("M 100644 {} creds.txt", secret_oid)))
+            .build();
+        for text in [with_blobs, without_blobs] {
+            let reader = Cursor::new(text.to_string());
+            let mut writer = Cursor::new(vec![]);
+            let mut filter_options: FilterOptions<_> = (&mut writer).into();
+            filter_options.default_include = true;
+            filter_options.strip_blobs_with_ids.insert(secret_oid.clone());
+            let filter_rules = vec![FilterRulePathExclude("nothing".into())];
+            let state = filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
+
+            let mut s = String::from("");
+            writer.set_position(0);
+            writer.read_to_string(&mut s).unwrap();
+            assert!(s.contains(" a.txt\n"));
+            assert!(!s.contains("creds.txt"));
+            assert!(!s.contains("secret"));
+            assert!(!s.contains(&secret_oid));
+            assert!(state.stripped_paths.contains("creds.txt"));
+        }
+    }
+
+    #[test]
+    fn replace_text_updates_blob_data_size() {
+        let text = TestStream::new()
+            .blob(":1", "password=hunter2\n")
+            .reset("refs/heads/master")
+            .commit(commit(":2", "one\n").op("M 100644 :1 a.txt"))
+            .build();
+        let reader = Cursor::new(text.to_string());
+        let mut writer = Cursor::new(vec![]);
+        let mut filter_options: FilterOptions<_> = (&mut writer).into();
+        filter_options.replace_text = Some(ReplaceText::parse_from_str("hunter2").unwrap());
+        filter_stream_with_cb(reader, filter_options, |_| Ok(true)).unwrap();
+
+        let mut s = String::from("");
+        writer.set_position(0);
+        writer.read_to_string(&mut s).unwrap();
+        assert!(s.contains("data 23\npassword=***REMOVED***\n"));
+        assert!(!s.contains("hunter2"));
+    }
+
+    #[test]
+    fn replace_message_updates_message_data_size() {
+        let text = TestStream::new()
+            .reset("refs/heads/master")
+            .commit(commit(":1", "fix BUG-1234\n").op("M 100644 78981922613b2afb6025042ff6bd878ac1994e85 a.txt"))
+            .tag("v1", ":1", "for BUG-1234\n")
+            .build();
+        let reader = Cursor::new(text.to_string());
+        let mut writer = Cursor::new(vec![]);
+        let mut filter_options: FilterOptions<_> = (&mut writer).into();
+        filter_options.replace_message = Some(ReplaceText::parse_from_str("regex:BUG-(\\d+)==>issue #\\1").unwrap());
+        filter_stream_with_cb(reader, filter_options, |_| Ok(true)).unwrap();
+
+        let mut s = String::from("");
+        writer.set_position(0);
+        writer.read_to_string(&mut s).unwrap();
+        assert!(s.contains("data 16\nfix issue #1234\n"));
+        assert!(s.contains("data 16\nfor issue #1234\n"));
+    }
+
+    // an empty git repo in a temp directory that gets
+    // deleted when dropped. used to check that our output
+    // is something git fast-import actually accepts.
+    struct TempRepo {
+        path: PathBuf,
+    }
+
+    impl TempRepo {
+        fn new(name: &str) -> TempRepo {
+            TempRepo::with_object_format(name, "sha1")
+        }
+
+        fn with_object_format(name: &str, object_format: &str) -> TempRepo {
+            let path = std::env::temp_dir().join(format!("gitfilter-test-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            let repo = TempRepo { path };
+            repo.git(&["init", "-q", &format!("--object-format={}", object_format)]);
+            repo
+        }
+
+        fn git(&self, args: &[&str]) -> String {
+            let output = std::process::Command::new("git")
+                .args(args)
+                .current_dir(&self.path)
+                .output().unwrap();
+            assert!(output.status.success(), "git {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+            String::from_utf8_lossy(&output.stdout).into()
+        }
+
+        fn fast_import(&self, stream: &[u8]) {
+            let mut child = std::process::Command::new("git")
+                .args(&["fast-import", "--quiet"])
+                .current_dir(&self.path)
+                .stdin(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn().unwrap();
+            child.stdin.take().unwrap().write_all(stream).unwrap();
+            let output = child.wait_with_output().unwrap();
+            assert!(output.status.success(), "fast-import failed: {}", String::from_utf8_lossy(&output.stderr));
+        }
+    }
+
+    impl Drop for TempRepo {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn real_exports_with_lightweight_tags_and_shared_tips_round_trip() {
+        let repo = TempRepo::new("shared-tips");
+        repo.git(&["symbolic-ref", "HEAD", "refs/heads/main"]);
+        for name in &["a.txt", "b.txt"] {
+            std::fs::write(repo.path.join(name), name).unwrap();
+            repo.git(&["add", name]);
+            repo.git(&["-c", "user.name=agent", "-c", "user.email=agent@example.com", "commit", "-q", "-m", name]);
+        }
+        // fast-export writes these as resets without any data
+        repo.git(&["branch", "same-as-main"]);
+        repo.git(&["tag", "lightweight", "main~1"]);
+
+        let mut output = vec![];
+        let mut filter_options: FilterOptions<_> = (&mut output).into();
+        filter_options.refs = vec!["--all".into()];
+        filter_options.with_blobs = true;
+        filter_options.default_include = true;
+        let filter_rules = vec![FilterRulePathExclude("nothing".into())];
+        filter_with_rules(filter_options, filter_rules, Some(&repo.path)).unwrap();
+
+        // nothing was filtered out, so every ref is exactly the same
+        let copy = TempRepo::new("shared-tips-copy");
+        copy.fast_import(&output);
+        let refs = |repo: &TempRepo| repo.git(&["for-each-ref", "--format=%(objectname) %(refname)"]);
+        assert_eq!(refs(&copy), refs(&repo));
+        assert!(refs(&copy).contains("refs/tags/lightweight"));
+    }
+
+    #[test]
+    fn mutated_objects_round_trip_through_fast_import() {
+        let text = TestStream::new()
+            .blob(":1", "a\n")
+            .reset("refs/heads/master")
+            .commit(commit(":2", "one\n").op("M 100644 :1 a.txt"))
+            .tag("v1", ":2", "tag msg\n")
+            .build();
+        let reader = Cursor::new(text.to_string());
+        let mut output = vec![];
+        filter_stream_with_cb(reader, &mut output, |obj| {
+            match &mut obj.object_type {
+                StructuredObjectType::Blob(blob) => {
+                    blob.data = b"much longer file contents\n".to_vec();
+                }
+                StructuredObjectType::Commit(commit) => {
+                    commit.commit_message = "a much longer commit message\n\nwith a body\n".into();
+                }
+                StructuredObjectType::Tag(tag) => {
+                    tag.tag_message = "t\n".into();
+                }
+                _ => {}
+            }
+            Ok(true)
+        }).unwrap();
+
+        let repo = TempRepo::new("round-trip");
+        repo.fast_import(&output);
+        assert_eq!(repo.git(&["cat-file", "-p", "master:a.txt"]), "much longer file contents\n");
+        assert_eq!(repo.git(&["log", "-1", "--format=%B", "master"]), "a much longer commit message\n\nwith a body\n\n");
+        assert_eq!(repo.git(&["tag", "-l", "--format=%(contents)", "v1"]), "t\n\n");
+    }
+
+    #[test]
+    fn direct_filter_can_write_commit_and_ref_maps() {
+        let repo = TempRepo::new("maps");
+        repo.git(&["symbolic-ref", "HEAD", "refs/heads/master"]);
+        let commit_file = |name: &str| {
+            std::fs::write(repo.path.join(name), name).unwrap();
+            repo.git(&["add", name]);
+            repo.git(&["-c", "user.name=agent", "-c", "user.email=agent@example.com", "commit", "-q", "-m", name]);
+            repo.git(&["rev-parse", "HEAD"]).trim().to_string()
+        };
+        let first = commit_file("a.txt");
+        let second = commit_file("b.txt");
+
+        let maps_dir = repo.path.join(".git").join("filter-repo");
+        let mut filter_options: FilterOptions<_> = sink().into();
+        filter_options.branch = Some("master".into());
+        filter_options.write_maps_to = Some(maps_dir.clone());
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).unwrap();
+
+        let new_first = repo.git(&["rev-parse", "master"]).trim().to_string();
+        let commit_map = std::fs::read_to_string(maps_dir.join("commit-map")).unwrap();
+        assert_eq!(commit_map, format!(
+            "{:<40} new\n{} {}\n{} {}\n",
+            "old", first, new_first, second, commit_map::ZERO_OID,
+        ));
+        let ref_map = std::fs::read_to_string(maps_dir.join("ref-map")).unwrap();
+        assert_eq!(ref_map, format!(
+            "{:<40} {:<40} ref\n{} {} refs/heads/master\n",
+            "old", "new", second, new_first,
+        ));
+        assert!(!maps_dir.join("fast-import-marks").exists());
+
+        // the marks file is removed when filtering fails too
+        let mut filter_options: FilterOptions<_> = sink().into();
+        filter_options.branch = Some("master".into());
+        filter_options.write_maps_to = Some(maps_dir.clone());
+        let filter_rules = vec![FilterRulePathIncludeGlob("a[z-a]".into())];
+        assert!(filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).is_err());
+        assert!(!maps_dir.join("fast-import-marks").exists());
+    }
+
+    #[test]
+    fn direct_filter_can_rewrite_hashes_in_messages() {
+        for object_format in ["sha1", "sha256"] {
+            rewrite_hashes_in_messages(object_format);
+        }
+    }
+
+    fn rewrite_hashes_in_messages(object_format: &str) {
+        let repo = TempRepo::with_object_format(&format!("hashes-{}", object_format), object_format);
+        repo.git(&["symbolic-ref", "HEAD", "refs/heads/master"]);
+        let commit = |files: &[&str], message: &str| {
+            for name in files {
+                std::fs::write(repo.path.join(name), message).unwrap();
+                repo.git(&["add", name]);
+            }
+            repo.git(&["-c", "user.name=agent", "-c", "user.email=agent@example.com", "commit", "-q", "-m", message]);
+            repo.git(&["rev-parse", "HEAD"]).trim().to_string()
+        };
+        let first = commit(&["a.txt", "b.txt"], "one");
+        commit(&["a.txt"], &format!("reverts {}\n\nsee {}", &first[..7], first));
+
+        let mut filter_options: FilterOptions<_> = sink().into();
+        filter_options.branch = Some("master".into());
+        filter_options.rewrite_commit_hashes = true;
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).unwrap();
+
+        let new_first = repo.git(&["rev-parse", "master~1"]).trim().to_string();
+        assert_ne!(first, new_first);
+        assert_eq!(
+            repo.git(&["log", "-1", "--format=%B", "master"]),
+            format!("reverts {}\n\nsee {}\n\n", &new_first[..7], new_first),
+        );
+
+        // there is no fast-import to learn the new hashes from
+        let mut filter_options: FilterOptions<_> = sink().into();
+        filter_options.rewrite_commit_hashes = true;
+        let reader = Cursor::new("done\n".to_string());
+        assert!(filter_stream_with_rules(reader, filter_options, vec![]).is_err());
+    }
+
+    #[test]
+    fn refs_with_dropped_tips_are_reset_to_kept_ancestors() {
+        let repo = TempRepo::new("dropped-tips");
+        repo.git(&["symbolic-ref", "HEAD", "refs/heads/master"]);
+        let commit = |name: &str| {
+            std::fs::write(repo.path.join(name), name).unwrap();
+            repo.git(&["add", name]);
+            repo.git(&["-c", "user.name=agent", "-c", "user.email=agent@example.com", "commit", "-q", "-m", name]);
+        };
+        commit("a.txt");
+        commit("b.txt");
+        // fast-export writes this as a reset with a from
+        repo.git(&["tag", "lightweight"]);
+        repo.git(&["checkout", "-q", "-b", "feature", "master~1"]);
+        commit("c.txt");
+
+        let mut output = vec![];
+        let mut filter_options: FilterOptions<_> = (&mut output).into();
+        filter_options.refs = vec!["--all".into()];
+        filter_options.with_blobs = true;
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        filter_with_rules(filter_options, filter_rules, Some(&repo.path)).unwrap();
+        let s = String::from_utf8(output.clone()).unwrap();
+        assert_eq!(s.matches("\ncommit ").count(), 1);
+        assert!(s.contains("reset refs/tags/lightweight\nfrom :"));
+        // fast-export labels the first commit with feature, which it
+        // reaches first, so its master tip is the one that gets dropped
+        assert!(s.contains("reset refs/heads/master\nfrom :"));
+        assert!(!s.contains("reset refs/heads/feature\nfrom"));
+        assert!(s.ends_with("done\n"));
+
+        let copy = TempRepo::new("dropped-tips-copy");
+        copy.fast_import(&output);
+        let master = copy.git(&["rev-parse", "master"]);
+        assert_eq!(copy.git(&["log", "--format=%s", "master"]), "a.txt\n");
+        assert_eq!(copy.git(&["rev-parse", "feature"]), master);
+        assert_eq!(copy.git(&["rev-parse", "lightweight"]), master);
+    }
+
+    #[test]
+    fn direct_filter_can_filter_many_refs_or_the_default_branch() {
+        let repo = TempRepo::new("many-refs");
+        repo.git(&["symbolic-ref", "HEAD", "refs/heads/main"]);
+        let commit = |files: &[&str], message: &str| {
+            for name in files {
+                std::fs::write(repo.path.join(name), message).unwrap();
+                repo.git(&["add", name]);
+            }
+            repo.git(&["-c", "user.name=agent", "-c", "user.email=agent@example.com", "commit", "-q", "-m", message]);
+        };
+        commit(&["a.txt", "b.txt"], "one");
+        repo.git(&["checkout", "-q", "-b", "release/1"]);
+        commit(&["a.txt"], "two");
+        repo.git(&["checkout", "-q", "-b", "release/2", "main"]);
+        commit(&["a.txt"], "three");
+        repo.git(&["checkout", "-q", "main"]);
+        let original_main = repo.git(&["rev-parse", "main"]);
+
+        let mut filter_options: FilterOptions<_> = sink().into();
+        filter_options.refs = vec!["refs/heads/release/*".into()];
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).unwrap();
+        // the shared commit was only rewritten once
+        let shared = repo.git(&["rev-parse", "release/1~1"]);
+        assert_eq!(repo.git(&["rev-parse", "release/2~1"]), shared);
+        assert_ne!(shared, original_main);
+        assert_eq!(repo.git(&["ls-tree", "--name-only", "release/2"]), "a.txt\n");
+        assert_eq!(repo.git(&["rev-parse", "main"]), original_main);
+
+        // without a branch, the branch that HEAD points to is used
+        let filter_options: FilterOptions<_> = sink().into();
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).unwrap();
+        assert_eq!(repo.git(&["rev-parse", "main"]), shared);
+
+        // a glob that matches nothing is an error, instead of filtering nothing
+        let mut filter_options: FilterOptions<_> = sink().into();
+        filter_options.refs = vec!["refs/heads/nothing/*".into()];
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        let err = filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).unwrap_err();
+        assert!(err.to_string().contains("refs/heads/nothing/*"), "{}", err);
+
+        // refs that point at the same commit are all rewritten to it,
+        // and so are lightweight tags
+        repo.git(&["branch", "copy-of-1", "release/1"]);
+        repo.git(&["tag", "v1", "release/1"]);
+        let mut filter_options: FilterOptions<_> = sink().into();
+        filter_options.refs = vec!["release/1".into(), "copy-of-1".into(), "v1".into()];
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).unwrap();
+        let release_1 = repo.git(&["rev-parse", "release/1"]);
+        assert_eq!(repo.git(&["rev-parse", "copy-of-1"]), release_1);
+        assert_eq!(repo.git(&["rev-parse", "v1"]), release_1);
+        assert_eq!(repo.git(&["ls-tree", "--name-only", "v1"]), "a.txt\n");
+
+        // refs that look like options are rejected, instead of being passed to git
+        let mut filter_options: FilterOptions<_> = sink().into();
+        filter_options.refs = vec!["--output=nope".into()];
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        let err = filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).unwrap_err();
+        assert!(err.to_string().contains("--output=nope"), "{}", err);
+        assert!(!repo.path.join("nope").exists());
+
+        // so is a detached HEAD without a branch
+        repo.git(&["checkout", "-q", "--detach", "main"]);
+        let filter_options: FilterOptions<_> = sink().into();
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        assert!(filter_with_rules_direct_ex(filter_options, filter_rules, Some(&repo.path)).is_err());
+        assert_eq!(repo.git(&["rev-parse", "main"]), shared);
+    }
+
+    #[test]
+    fn excluded_parents_resolve_through_a_previous_commit_map() {
+        let source = TempRepo::new("incremental-source");
+        source.git(&["symbolic-ref", "HEAD", "refs/heads/main"]);
+        let commit = |files: &[&str], message: &str| {
+            for name in files {
+                std::fs::write(source.path.join(name), message).unwrap();
+                source.git(&["add", name]);
+            }
+            source.git(&["-c", "user.name=agent", "-c", "user.email=agent@example.com", "commit", "-q", "-m", message]);
+            source.git(&["rev-parse", "HEAD"]).trim().to_string()
+        };
+        let run_filter = |exclude_revs: Vec<String>, previous_commit_map: HashMap<String, String>| {
+            let mut output = vec![];
+            let mut filter_options: FilterOptions<_> = (&mut output).into();
+            filter_options.with_blobs = true;
+            filter_options.exclude_revs = exclude_revs;
+            filter_options.previous_commit_map = previous_commit_map;
+            let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+            filter_with_rules(filter_options, filter_rules, Some(&source.path)).map(|_| output)
+        };
+        commit(&["a.txt", "b.txt"], "one");
+        let last_sync = commit(&["a.txt"], "two");
+
+        let target = TempRepo::new("incremental-target");
+        target.fast_import(&run_filter(vec![], HashMap::new()).unwrap());
+        let synced = target.git(&["rev-parse", "main"]).trim().to_string();
+
+        commit(&["a.txt", "b.txt"], "three");
+        let mut previous_commit_map = HashMap::new();
+        previous_commit_map.insert(last_sync.clone(), synced.clone());
+        let output = run_filter(vec![last_sync], previous_commit_map).unwrap();
+        let s = String::from_utf8(output.clone()).unwrap();
+        assert!(!s.contains("data 4\none\n") && !s.contains("data 4\ntwo\n"));
+        assert!(s.contains(&format!("from {}\n", synced)));
+
+        target.fast_import(&output);
+        assert_eq!(target.git(&["log", "--format=%s", "main"]), "three\ntwo\none\n");
+        assert_eq!(target.git(&["rev-parse", "main~1"]).trim(), synced);
+        assert_eq!(target.git(&["ls-tree", "--name-only", "main"]), "a.txt\n");
+
+        // the next sync was at a commit that only changed b.txt, so it
+        // was pruned, and main was left at its nearest kept ancestor
+        let last_sync = commit(&["b.txt"], "four");
+        let synced = target.git(&["rev-parse", "main"]).trim().to_string();
+        commit(&["a.txt"], "five");
+        let mut previous_commit_map = HashMap::new();
+        previous_commit_map.insert(last_sync.clone(), commit_map::ZERO_OID.to_string());
+        // without knowing where main was left, five would become a new root
+        assert!(run_filter(vec![last_sync.clone()], previous_commit_map.clone()).is_err());
+
+        let ref_map = commit_map::parse_ref_map(&format!(
+            "{:<40} {:<40} ref\n{} {} refs/heads/main\n", "old", "new", last_sync, synced,
+        ));
+        commit_map::add_pruned_ref_tips(&mut previous_commit_map, &ref_map);
+        let output = run_filter(vec![last_sync], previous_commit_map).unwrap();
+        target.fast_import(&output);
+        assert_eq!(target.git(&["log", "--format=%s", "main"]), "five\nthree\ntwo\none\n");
+        assert_eq!(target.git(&["rev-parse", "main~1"]).trim(), synced);
+    }
+
+    #[test]
+    fn bad_data_sizes_are_errors_instead_of_lost_history() {
+        let text = TestStream::new()
+            .reset("refs/heads/master")
+            .commit(commit(":1", "one\n").at(100).op("M 100644 78981922613b2afb6025042ff6bd878ac1994e85 a.txt"))
+            .commit(commit(":2", "two\n").at(200).from(":1").op("M 100644 61780798228d17af2d34fce4cfbdf35556832472 a.txt"))
+            .build()
+            .replace("data 4\ntwo", "data four\ntwo");
+        let reader = Cursor::new(text.to_string());
+        let filter_options: FilterOptions<_> = sink().into();
+        let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+        let err = filter_stream_with_rules(reader, filter_options, filter_rules).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&test_oid('c', ":2")));
+    }
+
+    #[test]
+    fn commits_can_be_filtered_by_metadata() {
+        let text = TestStream::new()
+            .reset("refs/heads/master")
+            .commit(commit(":1", "one\n").by("alice").at(100)
+                .op("M 100644 78981922613b2afb6025042ff6bd878ac1994e85 a.txt"))
+            .commit(commit(":2", "two\n").by("bob").committed_by("alice").at(200).from(":1")
+                .op("M 100644 61780798228d17af2d34fce4cfbdf35556832472 b.txt"))
+            .commit(commit(":3", "three\n").by("alice").at(300).from(":2")
+                .op("M 100644 61780798228d17af2d34fce4cfbdf35556832472 c.txt"))
+            .build();
+        let run_filter = |filter_rules: FilterRules| -> String {
+            let reader = Cursor::new(text.to_string());
+            let mut writer = Cursor::new(vec![]);
+            let mut filter_options: FilterOptions<_> = (&mut writer).into();
+            filter_options.default_include = true;
+            filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
+            let mut s = String::from("");
+            writer.set_position(0);
+            writer.read_to_string(&mut s).unwrap();
+            s
+        };
+
+        // bob's commit is dropped, and the next commit is rewired to its parent
+        let s = run_filter(vec![FilterRuleAuthorExclude("<bob@".into())]);
+        assert!(!s.contains("mark :2"));
+        assert!(s.contains("mark :3\n"));
+        assert!(s.contains("from :1\n"));
+
+        let s = run_filter(vec![FilterRuleCommitterInclude("^alice ".into())]);
+        assert!(s.contains("mark :1\n") && s.contains("mark :2\n") && s.contains("mark :3\n"));
+
+        // the first commit is dropped, so the second one becomes the root
+        let s = run_filter(vec![FilterRuleCommittedAfter(150)]);
+        assert!(!s.contains("mark :1"));
+        assert!(!s.contains("from :1"));
+        assert!(s.contains("from :2\n"));
+
+        let s = run_filter(vec![FilterRuleCommittedAfter(150), FilterRuleCommittedBefore(300)]);
+        assert!(s.contains("mark :2\n"));
+        assert!(!s.contains("mark :1") && !s.contains("mark :3"));
+
+        let reader = Cursor::new(text.to_string());
+        let res = filter_stream_with_rules(reader, sink().into(), vec![FilterRuleAuthorInclude("a(".into())]);
+        assert!(res.is_err());
+    }
 
     #[test]
-    fn filter_path_works() {
-        let writer = sink();
-        filter_with_cb(writer, NO_LOCATION, |obj| {
+    fn dropping_commits_in_a_callback_rewires_children() {
+        let text = TestStream::new()
+            .blob(":10", "a\n")
+            .blob(":11", "b\n")
+            .reset("refs/heads/master")
+            .commit(commit(":1", "one\n").by("alice").at(100).op("M 100644 :10 a.txt"))
+            .commit(commit(":2", "two\n").by("jerry").at(200).from(":1").op("M 100644 :11 b.txt"))
+            .commit(commit(":3", "three\n").by("alice").at(300).from(":2").op("M 100644 :11 c.txt"))
+            .tag("v1", ":2", "tag\n")
+            .build();
+        let reader = Cursor::new(text.to_string());
+        let mut output = vec![];
+        filter_stream_with_cb(reader, &mut output, |obj| {
             match &obj.object_type {
-                StructuredObjectType::Blob(_) => Ok(true),
                 StructuredObjectType::Commit(commit_obj) => {
-                    if commit_obj.committer.email.contains("jerry") {
-                        Ok(false)
-                    } else {
-                        Ok(true)
-                    }
-                },
+                    Ok(!commit_obj.committer.email.contains("jerry"))
+                }
                 _ => Ok(true),
             }
         }).unwrap();
+        let s = String::from_utf8(output.clone()).unwrap();
+        assert!(!s.contains("mark :2"));
+        assert!(!s.contains(":2\n"));
+        assert!(s.contains("mark :3\n"));
+        assert!(s.contains("tag v1\nfrom :1\n"));
+
+        let repo = TempRepo::new("callback-drop");
+        repo.fast_import(&output);
+        assert_eq!(repo.git(&["log", "--format=%s", "master"]), "three\none\n");
     }
 
     #[test]
-    fn can_modify_filter_objects() {
-        let mut writer = Cursor::new(vec![]);
-        filter_with_cb(&mut writer, NO_LOCATION, |obj| {
-            if let Some(reset) = &mut obj.has_reset {
-                *reset = "refs/heads/NEWBRANCHNAMEAAAAAAA".into();
-            }
-            match &mut obj.object_type {
-                StructuredObjectType::Blob(_) => Ok(true),
+    fn refs_with_tips_dropped_in_a_callback_are_reset() {
+        let text = TestStream::new()
+            .blob(":10", "a\n")
+            .reset("refs/heads/master")
+            .commit(commit(":1", "one\n").by("alice").op("M 100644 :10 a.txt"))
+            .commit(commit(":2", "two\n").by("jerry").from(":1").op("M 100644 :10 b.txt"))
+            .reset_from("refs/tags/lightweight", ":2")
+            .commit(commit(":3", "three\n").on("refs/heads/feature").by("jerry").from(":1").op("M 100644 :10 c.txt"))
+            .build();
+        let reader = Cursor::new(text);
+        let mut output = vec![];
+        filter_stream_with_cb(reader, &mut output, |obj| {
+            match &obj.object_type {
                 StructuredObjectType::Commit(commit_obj) => {
-                    commit_obj.commit_ref = commit_obj.commit_ref.replace("master", "NEWBRANCHNAMEAAAAAAA");
-                    Ok(true)
-                },
+                    Ok(!commit_obj.committer.email.contains("jerry"))
+                }
                 _ => Ok(true),
             }
         }).unwrap();
+        let s = String::from_utf8(output.clone()).unwrap();
+        assert!(s.contains("reset refs/tags/lightweight\nfrom :1\n"));
+        assert!(s.contains("reset refs/heads/feature\nfrom :1\n"));
+        // master was already left at :1
+        assert!(!s.contains("reset refs/heads/master\nfrom"));
+        assert!(s.ends_with("done\n"));
+
+        let repo = TempRepo::new("callback-dropped-tips");
+        repo.fast_import(&output);
+        let master = repo.git(&["rev-parse", "master"]);
+        assert_eq!(repo.git(&["rev-parse", "feature"]), master);
+        assert_eq!(repo.git(&["rev-parse", "lightweight"]), master);
+    }
+
+    #[test]
+    fn empty_commits_follow_the_prune_policy() {
+        let text = TestStream::new()
+            .reset("refs/heads/master")
+            .commit(commit(":1", "one\n").at(100).op("M 100644 78981922613b2afb6025042ff6bd878ac1994e85 a.txt"))
+            .commit(commit(":2", "release\n").at(200))
+            .commit(commit(":3", "three\n").at(300).from(":2")
+                .op("M 100644 61780798228d17af2d34fce4cfbdf35556832472 b.txt"))
+            .build();
+        let run_filter = |prune_empty: PruneEmpty| -> String {
+            let reader = Cursor::new(text.to_string());
+            let mut writer = Cursor::new(vec![]);
+            let mut filter_options: FilterOptions<_> = (&mut writer).into();
+            filter_options.prune_empty = prune_empty;
+            let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+            filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
+            let mut s = String::from("");
+            writer.set_position(0);
+            writer.read_to_string(&mut s).unwrap();
+            s
+        };
+        // :2 was empty to begin with, :3 became empty because of the filter
+        let s = run_filter(PruneEmpty::Auto);
+        assert!(s.contains("mark :2\n"));
+        assert!(!s.contains("mark :3"));
+        let s = run_filter(PruneEmpty::Always);
+        assert!(!s.contains("mark :2") && !s.contains("mark :3"));
+        let s = run_filter(PruneEmpty::Never);
+        assert!(s.contains("mark :2\n") && s.contains("mark :3\n"));
+        assert!(!s.contains("b.txt"));
+    }
+
+    #[test]
+    fn degenerate_merges_follow_the_prune_policy() {
+        let a = "M 100644 78981922613b2afb6025042ff6bd878ac1994e85 a.txt";
+        let b = "M 100644 61780798228d17af2d34fce4cfbdf35556832472 b.txt";
+        let text = TestStream::new()
+            .reset("refs/heads/master")
+            .commit(commit(":1", "one\n").at(100).op(a))
+            .commit(commit(":2", "two\n").on("refs/heads/feature").at(200).from(":1").op(b))
+            .commit(commit(":3", "three\n").at(300).from(":1")
+                .op("M 100644 61780798228d17af2d34fce4cfbdf35556832472 a.txt"))
+            .commit(commit(":4", "merge\n").at(400).from(":3").merge(":2").op(b))
+            .commit(commit(":5", "five\n").at(500).from(":4").op(a))
+            .build();
+        let run_filter = |prune_degenerate: PruneEmpty| -> String {
+            let reader = Cursor::new(text.to_string());
+            let mut writer = Cursor::new(vec![]);
+            let mut filter_options: FilterOptions<_> = (&mut writer).into();
+            filter_options.prune_degenerate = prune_degenerate;
+            let filter_rules = vec![FilterRulePathInclude("a.txt".into())];
+            filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
+            let mut s = String::from("");
+            writer.set_position(0);
+            writer.read_to_string(&mut s).unwrap();
+            s
+        };
+        // :2 is pruned, so the merge now merges :1 into :3,
+        // but :1 is already an ancestor of :3
+        let s = run_filter(PruneEmpty::Auto);
+        assert!(!s.contains("mark :4"));
+        assert!(s.contains("mark :5\n"));
+        assert!(s.contains("from :3\nM 100644 78981922613b2afb6025042ff6bd878ac1994e85 a.txt"));
+        let s = run_filter(PruneEmpty::Never);
+        assert!(s.contains("mark :4\n"));
+        assert!(s.contains("from :3\nmerge :1\n"));
+        assert!(s.contains("from :4\n"));
+    }
+
+    #[test]
+    fn can_parse_sizes() {
+        assert_eq!(parse_size("500").unwrap(), 500);
+        assert_eq!(parse_size("10K").unwrap(), 10 * 1024);
+        assert_eq!(parse_size("5m").unwrap(), 5 * 1024 * 1024);
+        assert_eq!(parse_size("1G").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("10X").is_err());
+    }
+
+    #[test]
+    fn tags_get_repointed_to_kept_ancestor() {
+        let text = TestStream::new()
+            .reset("refs/heads/master")
+            .commit(commit(":1", "one\n").op("M 100644 78981922613b2afb6025042ff6bd878ac1994e85 b.txt"))
+            .commit(commit(":2", "two\n").from(":1").op("M 100644 61780798228d17af2d34fce4cfbdf35556832472 a.txt"))
+            .tag("v1", ":2", "tag msg\n")
+            .build();
+        let reader = Cursor::new(text.to_string());
+        let mut writer = Cursor::new(vec![]);
+        let filter_options: FilterOptions<_> = (&mut writer).into();
+        let filter_rules = vec![FilterRulePathInclude("b.txt".into())];
+        filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
 
         let mut s = String::from("");
         writer.set_position(0);
         writer.read_to_string(&mut s).unwrap();
-        assert!(s.contains("refs/heads/NEWBRANCHNAMEAAAAAAA"));
-        assert!(!s.contains("refs/heads/master"));
+        // the tag pointed to :2 which was pruned, so it
+        // should now point to :1
+        assert!(s.contains("tag v1\nfrom :1\n"));
+        assert!(s.contains("tagger agent <agent@example.com> 1792312127 +0000\ndata 8\ntag msg\n"));
+
+        // now if we exclude everything the tag points to, it should be dropped
+        let reader = Cursor::new(text.to_string());
+        let mut writer = Cursor::new(vec![]);
+        let filter_options: FilterOptions<_> = (&mut writer).into();
+        let filter_rules = vec![FilterRulePathInclude("c.txt".into())];
+        filter_stream_with_rules(reader, filter_options, filter_rules).unwrap();
+        let mut s = String::from("");
+        writer.set_position(0);
+        writer.read_to_string(&mut s).unwrap();
+        assert!(!s.contains("tag v1"));
     }
 
     // used for tests to easily say:
@@ -705,6 +2569,198 @@ mod test {
         assert_eq!(new_fileops, expected);
     }
 
+    // used for tests to apply a commit of modifies so that
+    // the filter state knows about these files.
+    // the commit has the mark :base
+    fn track_existing_files(filter_state: &mut FilterState, files: &[(&str, &str)]) {
+        filter_state.start_commit_files(None, &[]);
+        for (path, dataref) in files {
+            let fileop = FileOpsOwned::FileModify(
+                "100644".into(), dataref.to_string(), path.to_string(),
+            );
+            filter_state.track_fileop(&fileop);
+        }
+        filter_state.finish_commit_files(Some(":base"));
+    }
+
+    // a commit with this mark, whose parent is the :base commit
+    fn commit_after_base(mark: &str, fileops: Vec<FileOpsOwned>) -> StructuredCommit {
+        StructuredCommit {
+            mark: Some(mark.into()),
+            from: Some(":base".into()),
+            fileops,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn filter_rules_handle_rename_fileops() {
+        let mut filter_state = FilterState::default();
+        track_existing_files(&mut filter_state, &[
+            ("lib/a.txt", ":1"), ("lib/b.txt", ":2"), ("other/c.txt", ":3"), ("other/d.txt", ":4"),
+        ]);
+        let mut commit = commit_after_base(":10", vec![
+            // inside the kept set
+            FileOpsOwned::FileRename("lib/a.txt".into(), "lib/a2.txt".into()),
+            // out of the kept set
+            FileOpsOwned::FileRename("lib/b.txt".into(), "other/b.txt".into()),
+            // into the kept set
+            FileOpsOwned::FileRename("other/c.txt".into(), "lib/c.txt".into()),
+            // not relevant at all
+            FileOpsOwned::FileRename("other/d.txt".into(), "other/d2.txt".into()),
+        ]);
+        let filter_rules = vec![
+            FilterRulePathInclude("lib/".into()),
+            FilterRulePathRename("lib/".into(), "".into()),
+        ];
+
+        let new_fileops = apply_filter_rules_to_fileops(
+            false,
+            &mut filter_state,
+            &mut commit,
+            &filter_rules
+        );
+
+        let expected = vec![
+            FileOpsOwned::FileRename("a.txt".into(), "a2.txt".into()),
+            FileOpsOwned::FileDelete("b.txt".into()),
+            FileOpsOwned::FileModify("100644".into(), ":3".into(), "c.txt".into()),
+        ];
+        eprintln!("Actual: {:#?}", new_fileops);
+        eprintln!("Expected: {:#?}", expected);
+        assert_eq!(new_fileops, expected);
+
+        // the state should follow the original renames, so
+        // renaming it back into the kept set still knows the blob
+        assert_eq!(filter_state.get_commit_file(":10", "other/b.txt"), Some(&("100644".into(), ":2".into())));
+        assert!(filter_state.get_commit_file(":10", "lib/b.txt").is_none());
+        // the parent still has it where it was
+        assert!(filter_state.get_commit_file(":base", "lib/b.txt").is_some());
+    }
+
+    #[test]
+    fn filter_rules_handle_copy_fileops() {
+        let mut filter_state = FilterState::default();
+        track_existing_files(&mut filter_state, &[
+            ("lib/a.txt", ":1"), ("\"other/my file.txt\"", ":2"),
+        ]);
+        let mut commit = commit_after_base(":10", vec![
+            FileOpsOwned::FileCopy("lib/a.txt".into(), "lib/a2.txt".into()),
+            FileOpsOwned::FileCopy("lib/a.txt".into(), "other/a.txt".into()),
+            FileOpsOwned::FileCopy("\"other/my file.txt\"".into(), "\"lib/my file.txt\"".into()),
+            FileOpsOwned::FileDeleteAll,
+            FileOpsOwned::NoteModify(":5".into(), ":6".into()),
+        ]);
+        let filter_rules = vec![FilterRulePathInclude("lib/".into())];
+
+        let new_fileops = apply_filter_rules_to_fileops(
+            false,
+            &mut filter_state,
+            &mut commit,
+            &filter_rules
+        );
+
+        let expected = vec![
+            FileOpsOwned::FileCopy("lib/a.txt".into(), "lib/a2.txt".into()),
+            FileOpsOwned::FileModify("100644".into(), ":2".into(), "\"lib/my file.txt\"".into()),
+            FileOpsOwned::FileDeleteAll,
+            FileOpsOwned::NoteModify(":5".into(), ":6".into()),
+        ];
+        eprintln!("Actual: {:#?}", new_fileops);
+        eprintln!("Expected: {:#?}", expected);
+        assert_eq!(new_fileops, expected);
+        // deleteall should clear the state
+        assert!(filter_state.get_commit_file(":10", "lib/a.txt").is_none());
+        assert!(filter_state.get_commit_file(":10", "lib/a2.txt").is_none());
+    }
+
+    #[test]
+    fn filter_rules_handle_renames_on_branches() {
+        let mut filter_state = FilterState::default();
+        let filter_rules = vec![FilterRulePathInclude("keep/".into())];
+        let filter = |filter_state: &mut FilterState, commit: StructuredCommit| {
+            let mut commit = commit;
+            apply_filter_rules_to_fileops(false, filter_state, &mut commit, &filter_rules)
+        };
+        // master :1 adds src/x
+        filter(&mut filter_state, StructuredCommit {
+            mark: Some(":1".into()),
+            fileops: vec![FileOpsOwned::FileModify("100644".into(), ":A".into(), "src/x".into())],
+            ..Default::default()
+        });
+        // feature :2 changes src/x
+        filter(&mut filter_state, StructuredCommit {
+            mark: Some(":2".into()),
+            from: Some(":1".into()),
+            fileops: vec![FileOpsOwned::FileModify("100644".into(), ":B".into(), "src/x".into())],
+            ..Default::default()
+        });
+        // master :3 renames src/x, which is still blob A on master
+        let new_fileops = filter(&mut filter_state, StructuredCommit {
+            mark: Some(":3".into()),
+            from: Some(":1".into()),
+            fileops: vec![FileOpsOwned::FileRename("src/x".into(), "keep/x".into())],
+            ..Default::default()
+        });
+        assert_eq!(new_fileops, vec![
+            FileOpsOwned::FileModify("100644".into(), ":A".into(), "keep/x".into()),
+        ]);
+        // a merge of feature into master has the files of master (its from)
+        // plus its own changes, which fast-export writes relative to the from
+        let new_fileops = filter(&mut filter_state, StructuredCommit {
+            mark: Some(":4".into()),
+            from: Some(":3".into()),
+            merges: vec![":2".into()],
+            fileops: vec![
+                FileOpsOwned::FileModify("100644".into(), ":B".into(), "src/x".into()),
+                FileOpsOwned::FileCopy("src/x".into(), "keep/y".into()),
+                FileOpsOwned::FileRename("keep/x".into(), "keep/z".into()),
+            ],
+            ..Default::default()
+        });
+        assert_eq!(new_fileops, vec![
+            FileOpsOwned::FileModify("100644".into(), ":B".into(), "keep/y".into()),
+            FileOpsOwned::FileRename("keep/x".into(), "keep/z".into()),
+        ]);
+        assert_eq!(filter_state.get_commit_file(":4", "keep/z"), Some(&("100644".into(), ":A".into())));
+        // a new root commit doesnt have any of the files
+        let new_fileops = filter(&mut filter_state, StructuredCommit {
+            mark: Some(":5".into()),
+            fileops: vec![FileOpsOwned::FileRename("src/x".into(), "keep/x".into())],
+            ..Default::default()
+        });
+        assert!(new_fileops.is_empty());
+        // only the tips keep a snapshot of all of their files.
+        // the merged feature branch stopped being a tip
+        let mut tips: Vec<_> = filter_state.tip_files.keys().cloned().collect();
+        tips.sort();
+        assert_eq!(tips, vec![":4", ":5"]);
+        assert_eq!(filter_state.get_commit_file(":2", "src/x"), Some(&("100644".into(), ":B".into())));
+    }
+
+    #[test]
+    fn files_are_not_tracked_when_every_path_is_kept() {
+        let mut filter_state = FilterState::default();
+        let filter_rules = vec![FilterRuleToSubdirectoryFilter("sub".into())];
+        let mut commit = StructuredCommit {
+            mark: Some(":1".into()),
+            fileops: vec![FileOpsOwned::FileModify("100644".into(), ":A".into(), "x".into())],
+            ..Default::default()
+        };
+        apply_filter_rules_to_fileops(true, &mut filter_state, &mut commit, &filter_rules);
+        assert!(filter_state.file_changes.is_empty());
+        assert!(filter_state.tip_files.is_empty());
+
+        let filter_rules = vec![FilterRulePathExclude("x".into())];
+        let mut commit = StructuredCommit {
+            mark: Some(":2".into()),
+            fileops: vec![FileOpsOwned::FileModify("100644".into(), ":A".into(), "y".into())],
+            ..Default::default()
+        };
+        apply_filter_rules_to_fileops(true, &mut filter_state, &mut commit, &filter_rules);
+        assert_eq!(filter_state.get_commit_file(":2", "y"), Some(&("100644".into(), ":A".into())));
+    }
+
     #[test]
     fn filter_rules_handle_spaces() {
         let mut commit = current_commit_state(&[
@@ -855,6 +2911,294 @@ mod test {
         assert_eq!(new_fileops, expected);
     }
 
+    #[test]
+    fn filter_rules_handle_globs() {
+        let mut filter_state = FilterState::default();
+        let mut commit = current_commit_state(&[
+            "services/a/api/a.proto",
+            "services/b/b.proto",
+            "services/b/b.go",
+            "docs/img/a.png",
+            "docs/a.md",
+            "\"docs/my img/b.png\"",
+        ]);
+        let filter_rules = vec![
+            FilterRulePathIncludeGlob("**/*.proto".into()),
+            FilterRulePathIncludeGlob("docs/".into()),
+            FilterRulePathExcludeGlob("docs/**/*.png".into()),
+        ];
+        let new_fileops = apply_filter_rules_to_fileops(
+            false,
+            &mut filter_state,
+            &mut commit,
+            &filter_rules
+        );
+
+        let expected = vec![
+            "services/a/api/a.proto", "services/b/b.proto", "docs/a.md",
+        ];
+        let mut expected_fileops = vec![];
+        for path in expected {
+            expected_fileops.push(
+                FileOpsOwned::FileModify("".into(), "".into(), path.to_string())
+            );
+        }
+        assert_eq!(new_fileops, expected_fileops);
+    }
+
+    #[test]
+    fn filter_rules_handle_regexes() {
+        let mut filter_state = FilterState::default();
+        let mut commit = current_commit_state(&[
+            "services/users/api/user.proto",
+            "services/orders/api/order.proto",
+            "services/orders/api/generated.pb.go",
+            "services/orders/impl/order.go",
+        ]);
+        let filter_rules = vec![
+            FilterRulePathRenameRegex(r"^services/(\w+)/api/".into(), "api/$1/".into()),
+            FilterRulePathExcludeRegex(r"\.pb\.go$".into()),
+        ];
+        let new_fileops = apply_filter_rules_to_fileops(
+            false,
+            &mut filter_state,
+            &mut commit,
+            &filter_rules
+        );
+
+        let expected = vec![
+            "api/users/user.proto", "api/orders/order.proto",
+        ];
+        let mut expected_fileops = vec![];
+        for path in expected {
+            expected_fileops.push(
+                FileOpsOwned::FileModify("".into(), "".into(), path.to_string())
+            );
+        }
+        assert_eq!(new_fileops, expected_fileops);
+
+        let mut commit = current_commit_state(&["a.rs", "b.md", "c/d.rs"]);
+        let filter_rules = vec![FilterRulePathIncludeRegex(r"\.rs$".into())];
+        let new_fileops = apply_filter_rules_to_fileops(
+            false,
+            &mut filter_state,
+            &mut commit,
+            &filter_rules
+        );
+        let expected_fileops = vec![
+            FileOpsOwned::FileModify("".into(), "".into(), "a.rs".into()),
+            FileOpsOwned::FileModify("".into(), "".into(), "c/d.rs".into()),
+        ];
+        assert_eq!(new_fileops, expected_fileops);
+    }
+
+    #[test]
+    fn can_parse_filter_rules_from_paths_file() {
+        let text = r#"
+# this is a comment
+core
+literal:docs/
+glob:**/*.proto
+regex:^tools/.*\.py$
+literal:core/==>lib/core/
+regex:^services/(\w+)/api/==>api/\1/
+
+"#;
+        let filter_rules = parse_filter_rules_from_str(text).unwrap();
+        assert_eq!(filter_rules.len(), 6);
+        let mut filter_state = FilterState::default();
+        let mut commit = current_commit_state(&[
+            "core/a.rs",
+            "core-utils/a.rs",
+            "docs/a.md",
+            "x/y.proto",
+            "tools/build.py",
+            "tools/build.sh",
+            "services/users/api/user.go",
+        ]);
+        let new_fileops = apply_filter_rules_to_fileops(
+            false,
+            &mut filter_state,
+            &mut commit,
+            &filter_rules
+        );
+        let expected = vec![
+            "lib/core/a.rs", "docs/a.md", "x/y.proto", "tools/build.py", "api/users/user.go",
+        ];
+        let mut expected_fileops = vec![];
+        for path in expected {
+            expected_fileops.push(
+                FileOpsOwned::FileModify("".into(), "".into(), path.to_string())
+            );
+        }
+        assert_eq!(new_fileops, expected_fileops);
+
+        assert!(parse_filter_rules_from_str("glob:*.txt==>a.txt").is_err());
+        assert!(parse_filter_rules_from_str("regex:a(b").is_err());
+        assert!(parse_filter_rules_from_str("literal:==>a").is_err());
+    }
+
+    #[test]
+    fn filter_rules_handle_subdirectory_filter() {
+        let mut filter_state = FilterState::default();
+        let mut commit = StructuredCommit::default();
+        commit.fileops = vec![
+            FileOpsOwned::FileModify("100644".into(), ":1".into(), "lib/core/a.rs".into()),
+            FileOpsOwned::FileModify("100644".into(), ":2".into(), "\"lib/core/my file.rs\"".into()),
+            FileOpsOwned::FileModify("100644".into(), ":3".into(), "\"lib/core/caf\\303\\251.rs\"".into()),
+            FileOpsOwned::FileModify("100644".into(), ":4".into(), "lib/core-utils/b.rs".into()),
+            FileOpsOwned::FileDelete("lib/core/old.rs".into()),
+            FileOpsOwned::FileDelete("lib/core".into()),
+            FileOpsOwned::FileDelete("lib/other.rs".into()),
+        ];
+        // even with default include, the subdirectory filter excludes everything else
+        let filter_rules = vec![FilterRuleSubdirectoryFilter("lib/core/".into())];
+        assert!(validate_filter_rules(&filter_rules).is_ok());
+        let new_fileops = apply_filter_rules_to_fileops(
+            true,
+            &mut filter_state,
+            &mut commit,
+            &filter_rules
+        );
+        let expected = vec![
+            FileOpsOwned::FileModify("100644".into(), ":1".into(), "a.rs".into()),
+            FileOpsOwned::FileModify("100644".into(), ":2".into(), "\"my file.rs\"".into()),
+            FileOpsOwned::FileModify("100644".into(), ":3".into(), "\"caf\\303\\251.rs\"".into()),
+            FileOpsOwned::FileDelete("old.rs".into()),
+            FileOpsOwned::FileDeleteAll,
+        ];
+        eprintln!("Actual: {:#?}", new_fileops);
+        eprintln!("Expected: {:#?}", expected);
+        assert_eq!(new_fileops, expected);
+    }
+
+    #[test]
+    fn subdirectory_filter_excludes_everything_else() {
+        let mut path = "docs/readme.md".to_string();
+        // even if a rule after it includes the path
+        let filter_rules = vec![
+            FilterRuleSubdirectoryFilter("lib/core/".into()),
+            FilterRulePathInclude(PathPattern::directory("docs")),
+        ];
+        assert!(!should_use_file(&mut path, &filter_rules, false));
+        let mut path = "lib/core/a.rs".to_string();
+        assert!(should_use_file(&mut path, &filter_rules, false));
+        assert_eq!(path, "a.rs");
+    }
+
+    #[test]
+    fn subdirectory_filter_handles_deleting_the_directory() {
+        let mut filter_state = FilterState::default();
+        let filter_rules = vec![FilterRuleSubdirectoryFilter("lib/core/".into())];
+        // lib/core is a file here, so deleting it doesnt touch
+        // anything inside of the directory
+        track_existing_files(&mut filter_state, &[("lib/core", ":1")]);
+        let mut commit = commit_after_base(":10", vec![
+            FileOpsOwned::FileDelete("lib/core".into()),
+            FileOpsOwned::FileModify("100644".into(), ":2".into(), "lib/core/a.rs".into()),
+        ]);
+        let new_fileops = apply_filter_rules_to_fileops(false, &mut filter_state, &mut commit, &filter_rules);
+        assert_eq!(new_fileops, vec![
+            FileOpsOwned::FileModify("100644".into(), ":2".into(), "a.rs".into()),
+        ]);
+        // now its a directory, so deleting it deletes everything
+        let mut commit = StructuredCommit {
+            mark: Some(":11".into()),
+            from: Some(":10".into()),
+            fileops: vec![FileOpsOwned::FileDelete("lib/core".into())],
+            ..Default::default()
+        };
+        let new_fileops = apply_filter_rules_to_fileops(false, &mut filter_state, &mut commit, &filter_rules);
+        assert_eq!(new_fileops, vec![FileOpsOwned::FileDeleteAll]);
+    }
+
+    #[test]
+    fn filter_rules_handle_to_subdirectory_filter() {
+        let mut filter_state = FilterState::default();
+        let mut commit = StructuredCommit::default();
+        commit.fileops = vec![
+            FileOpsOwned::FileModify("100644".into(), ":1".into(), "a.rs".into()),
+            FileOpsOwned::FileModify("100644".into(), ":2".into(), "\"my file.rs\"".into()),
+            FileOpsOwned::FileDelete("src/b.rs".into()),
+        ];
+        let filter_rules = vec![FilterRuleToSubdirectoryFilter("my project/".into())];
+        let new_fileops = apply_filter_rules_to_fileops(
+            false,
+            &mut filter_state,
+            &mut commit,
+            &filter_rules
+        );
+        let expected = vec![
+            FileOpsOwned::FileModify("100644".into(), ":1".into(), "\"my project/a.rs\"".into()),
+            FileOpsOwned::FileModify("100644".into(), ":2".into(), "\"my project/my file.rs\"".into()),
+            FileOpsOwned::FileDelete("\"my project/src/b.rs\"".into()),
+        ];
+        eprintln!("Actual: {:#?}", new_fileops);
+        eprintln!("Expected: {:#?}", expected);
+        assert_eq!(new_fileops, expected);
+
+        for bad in &["", "/", "/abs", "a/../b", "a//b", "./a"] {
+            let filter_rules = vec![FilterRuleToSubdirectoryFilter(bad.to_string())];
+            assert!(validate_filter_rules(&filter_rules).is_err(), "{} should be invalid", bad);
+        }
+    }
+
+    #[test]
+    fn invalid_globs_are_rejected() {
+        let filter_rules = vec![FilterRulePathIncludeGlob("a[z-a]".into())];
+        assert!(validate_filter_rules(&filter_rules).is_err());
+        let filter_rules = vec![FilterRulePathIncludeGlob("**/*.proto".into())];
+        assert!(validate_filter_rules(&filter_rules).is_ok());
+        let filter_rules = vec![FilterRulePathRenameRegex("a(b".into(), "c".into())];
+        assert!(validate_filter_rules(&filter_rules).is_err());
+    }
+
+    #[test]
+    fn filter_rules_handle_match_modes() {
+        let files = [
+            "core/a.rs", "core-utils/b.rs", "core", "corelib.rs", "lib.rs", "library/c.rs",
+        ];
+        let run = |filter_rules: FilterRules| {
+            let mut filter_state = FilterState::default();
+            let mut commit = current_commit_state(&files);
+            let new_fileops = apply_filter_rules_to_fileops(
+                false,
+                &mut filter_state,
+                &mut commit,
+                &filter_rules
+            );
+            new_fileops.into_iter().map(|op| match op {
+                FileOpsOwned::FileModify(_, _, path) => path,
+                _ => panic!("Expected only file modifies"),
+            }).collect::<Vec<_>>()
+        };
+
+        // prefix is the default
+        let kept = run(vec![FilterRulePathInclude("core".into())]);
+        assert_eq!(kept, vec!["core/a.rs", "core-utils/b.rs", "core", "corelib.rs"]);
+
+        let kept = run(vec![FilterRulePathInclude(PathPattern::directory("core"))]);
+        assert_eq!(kept, vec!["core/a.rs", "core"]);
+        let kept = run(vec![FilterRulePathInclude(PathPattern::directory("core/"))]);
+        assert_eq!(kept, vec!["core/a.rs", "core"]);
+
+        let kept = run(vec![FilterRulePathInclude(PathPattern::exact_file("lib.rs"))]);
+        assert_eq!(kept, vec!["lib.rs"]);
+
+        let kept = run(vec![
+            FilterRulePathInclude(PathPattern::directory("")),
+            FilterRulePathExclude(PathPattern::directory("lib")),
+            FilterRulePathExclude(PathPattern::directory("core")),
+        ]);
+        assert_eq!(kept, vec!["core-utils/b.rs", "corelib.rs", "lib.rs", "library/c.rs"]);
+
+        let kept = run(vec![
+            FilterRulePathRename(PathPattern::directory("core"), "src/core".into()),
+            FilterRulePathRename(PathPattern::directory("library/"), "".into()),
+        ]);
+        assert_eq!(kept, vec!["src/core/a.rs", "src/core", "c.rs"]);
+    }
+
     #[test]
     fn filter_rules_correct_order() {
         let mut filter_state = FilterState::default();
@@ -0,0 +1,249 @@
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    // filter rules are just strings, so instead of compiling
+    // the pattern every time we check a path, we compile it once
+    // and keep it here. the key is the regex string, and the value
+    // is None if that string failed to compile.
+    static REGEX_CACHE: RefCell<HashMap<String, Option<Regex>>> = RefCell::new(HashMap::new());
+    // same as above, but the key is the glob, so we dont have to
+    // convert the glob to a regex string every time.
+    static GLOB_CACHE: RefCell<HashMap<String, Option<Regex>>> = RefCell::new(HashMap::new());
+}
+
+fn with_cached<O>(
+    cache: &'static std::thread::LocalKey<RefCell<HashMap<String, Option<Regex>>>>,
+    key: &str,
+    make_regex_str: impl FnOnce(&str) -> String,
+    cb: impl FnOnce(&Regex) -> O,
+) -> Option<O> {
+    cache.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if !cache.contains_key(key) {
+            let regex_str = make_regex_str(key);
+            cache.insert(key.to_string(), Regex::new(&regex_str).ok());
+        }
+        match cache.get(key) {
+            Some(Some(re)) => Some(cb(re)),
+            _ => None,
+        }
+    })
+}
+
+/// compile the regex (or get it from the cache) and
+/// pass it to the callback. returns None if the regex is invalid.
+pub fn with_cached_regex<O>(
+    regex_str: &str,
+    cb: impl FnOnce(&Regex) -> O,
+) -> Option<O> {
+    with_cached(&REGEX_CACHE, regex_str, |r| r.to_string(), cb)
+}
+
+/// like `with_cached_regex`, but for a glob.
+pub fn with_cached_glob<O>(
+    glob: &str,
+    cb: impl FnOnce(&Regex) -> O,
+) -> Option<O> {
+    with_cached(&GLOB_CACHE, glob, glob_to_regex, cb)
+}
+
+/// convert a gitignore style glob into a regex string that
+/// matches an entire path. the rules are:
+/// - `*` matches anything except a `/`
+/// - `?` matches any one character except a `/`
+/// - `[abc]`, `[a-z]`, `[!abc]` match one character in (or not in) the class
+/// - a leading `**/` matches in all directories
+/// - a trailing `/**` matches everything inside
+/// - `/**/` matches zero or more directories
+/// - if there is no `/` in the glob (other than a trailing one)
+///   then it can match at any directory level, otherwise it is
+///   relative to the root
+/// - a trailing `/` only matches directories
+/// - if the glob matches a directory, it matches everything inside of it
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut glob = glob;
+    let only_dirs = glob.len() > 1 && glob.ends_with('/');
+    if only_dirs {
+        glob = &glob[..glob.len() - 1];
+    }
+    let anchored = glob.contains('/');
+    if glob.starts_with('/') {
+        glob = &glob[1..];
+    }
+
+    let chars: Vec<char> = glob.chars().collect();
+    let mut out = String::from("^");
+    if !anchored {
+        out.push_str("(?:.*/)?");
+    }
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let at_segment_start = i == 0 || chars[i - 1] == '/';
+        match c {
+            '*' if chars.get(i + 1) == Some(&'*') && at_segment_start => {
+                if chars.get(i + 2) == Some(&'/') {
+                    // **/ matches zero or more directories
+                    out.push_str("(?:.*/)?");
+                    i += 3;
+                    continue;
+                } else if i + 2 == chars.len() {
+                    // trailing /** matches everything inside
+                    out.push_str(".*");
+                    i += 2;
+                    continue;
+                }
+                out.push_str("[^/]*");
+                i += 2;
+                continue;
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => {
+                match glob_class_to_regex(&chars[i..]) {
+                    Some((class_str, consumed)) => {
+                        out.push_str(&class_str);
+                        i += consumed;
+                        continue;
+                    }
+                    // no closing bracket, so its just a regular character
+                    None => out.push_str("\\["),
+                }
+            }
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                out.push_str(&regex::escape(&chars[i].to_string()));
+            }
+            _ => out.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+
+    if only_dirs {
+        out.push_str("/.*$");
+    } else {
+        out.push_str("(?:/.*)?$");
+    }
+    out
+}
+
+/// chars must start with `[`. returns the regex character class
+/// and how many chars of the glob were consumed, or None if the class
+/// is not closed.
+pub(crate) fn glob_class_to_regex(chars: &[char]) -> Option<(String, usize)> {
+    let mut out = String::from("[");
+    let mut i = 1;
+    if let Some('!') | Some('^') = chars.get(i) {
+        out.push('^');
+        i += 1;
+    }
+    // a ] right after the opening bracket is part of the class
+    if chars.get(i) == Some(&']') {
+        out.push_str("\\]");
+        i += 1;
+    }
+    while i < chars.len() {
+        match chars[i] {
+            ']' => {
+                out.push(']');
+                return Some((out, i + 1));
+            }
+            // these have special meaning in regex classes, but not in globs
+            c @ '[' | c @ '&' | c @ '~' | c @ '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    None
+}
+
+/// returns true if the glob matches this path, or a directory
+/// that this path is inside of. invalid globs never match.
+pub fn glob_matches(glob: &str, path: &str) -> bool {
+    with_cached_glob(glob, |re| re.is_match(path)).unwrap_or(false)
+}
+
+pub fn is_valid_glob(glob: &str) -> bool {
+    with_cached_glob(glob, |_| ()).is_some()
+}
+
+/// returns true if the regex matches anywhere in the path.
+/// invalid regexes never match.
+pub fn regex_matches(regex_str: &str, path: &str) -> bool {
+    with_cached_regex(regex_str, |re| re.is_match(path)).unwrap_or(false)
+}
+
+/// if the regex matches the path, returns the path with the first
+/// match replaced. the replacement can reference capture
+/// groups with `$1` or `${1}`. use `${1}` if the group is followed
+/// by a letter, number, or underscore.
+pub fn regex_replace(regex_str: &str, path: &str, replacement: &str) -> Option<String> {
+    with_cached_regex(regex_str, |re| {
+        if re.is_match(path) {
+            Some(re.replace(path, replacement).into_owned())
+        } else {
+            None
+        }
+    }).flatten()
+}
+
+pub fn is_valid_regex(regex_str: &str) -> bool {
+    with_cached_regex(regex_str, |_| ()).is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn globs_match_like_gitignore() {
+        assert!(glob_matches("**/*.proto", "a.proto"));
+        assert!(glob_matches("**/*.proto", "services/x/api/a.proto"));
+        assert!(!glob_matches("**/*.proto", "services/x/api/a.proto.bak"));
+
+        assert!(glob_matches("docs/**/*.png", "docs/a.png"));
+        assert!(glob_matches("docs/**/*.png", "docs/x/y/a.png"));
+        assert!(!glob_matches("docs/**/*.png", "other/docs/a.png"));
+
+        // no slash means it matches at any level
+        assert!(glob_matches("*.txt", "a.txt"));
+        assert!(glob_matches("*.txt", "x/y/a.txt"));
+        // but with a slash, its relative to the root
+        assert!(glob_matches("x/*.txt", "x/a.txt"));
+        assert!(!glob_matches("x/*.txt", "x/y/a.txt"));
+        assert!(!glob_matches("x/*.txt", "z/x/a.txt"));
+
+        assert!(glob_matches("src/**", "src/a/b/c.rs"));
+        assert!(!glob_matches("src/**", "src2/a.rs"));
+        // matching a directory matches everything inside of it
+        assert!(glob_matches("sr?", "src/a/b/c.rs"));
+        assert!(glob_matches("build/", "x/build/a.o"));
+        assert!(!glob_matches("build/", "x/build"));
+
+        assert!(glob_matches("file[0-9].txt", "file1.txt"));
+        assert!(!glob_matches("file[!0-9].txt", "file1.txt"));
+        assert!(glob_matches("file[!0-9].txt", "fileA.txt"));
+        assert!(glob_matches("a+b(c).txt", "a+b(c).txt"));
+        assert!(glob_matches("\\*.txt", "*.txt"));
+        assert!(!glob_matches("\\*.txt", "a.txt"));
+    }
+
+    #[test]
+    fn regex_replace_uses_capture_groups() {
+        let re = r"services/(\w+)/api/";
+        let replaced = regex_replace(re, "services/users/api/user.proto", "api/$1/");
+        assert_eq!(replaced, Some("api/users/user.proto".into()));
+        let replaced = regex_replace(re, "services/users/impl/user.go", "api/$1/");
+        assert_eq!(replaced, None);
+
+        let replaced = regex_replace(r"^(.*)\.txt$", "a/b.txt", "${1}_old.md");
+        assert_eq!(replaced, Some("a/b_old.md".into()));
+        assert!(!is_valid_regex("a(b"));
+        assert!(!regex_matches("a(b", "a(b"));
+    }
+}
@@ -1,10 +1,93 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, BTreeSet, BTreeMap};
+use super::export_parser::{FileOpsOwned, StructuredCommit, StructuredTag};
+use super::commit_map::is_zero_oid;
+
+/// what a ref pointed to in the original history
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RefTip {
+    pub original_oid: String,
+    /// the mark of the commit, if the ref points to a commit in the stream
+    pub mark: Option<String>,
+}
+
+/// the files that one commit changed, compared to its first parent.
+/// fast-export writes the fileops of merges relative to the first
+/// parent too, so the other parents dont matter here.
+#[derive(Debug, Default, Clone)]
+pub struct FileChanges {
+    /// the mark (or oid) of the first parent
+    pub parent: Option<String>,
+    /// if the commit had a deleteall, none of the
+    /// files of the parent exist anymore
+    pub deleted_all: bool,
+    /// path -> (mode, dataref), or None if the path was deleted
+    pub files: HashMap<String, Option<(String, String)>>,
+}
+
+/// path -> (mode, dataref) of every file that exists in a commit
+pub type CommitFiles = HashMap<String, (String, String)>;
+
+fn apply_file_changes(files: &mut CommitFiles, changes: &FileChanges) {
+    if changes.deleted_all {
+        files.clear();
+    }
+    for (path, info) in &changes.files {
+        match info {
+            Some(info) => { files.insert(path.clone(), info.clone()); }
+            None => { files.remove(path); }
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct FilterState {
     pub have_used_a_commit: bool,
     pub mark_map: HashMap<String, String>,
     pub graph: HashMap<String, Vec<String>>,
+    /// the original (unfiltered) file changes of the commit that is
+    /// currently being filtered. together with parent_files, this
+    /// knows the (mode, dataref) of every file that exists in the commit.
+    /// this is needed to turn a rename/copy from a path that we exclude
+    /// into a modify of a path that we include. see `get_file`
+    pub current_files: FileChanges,
+    /// all of the files of the from of the current commit
+    pub parent_files: CommitFiles,
+    /// the original file changes of every commit we have seen, by mark.
+    /// commits on other branches dont see each others changes,
+    /// because the files of a commit only come from its own parents.
+    pub file_changes: HashMap<String, FileChanges>,
+    /// all of the files of every commit that doesnt have a child yet,
+    /// by mark. the snapshot moves to the child when it starts, so
+    /// a linear history never has to walk back through file_changes.
+    pub tip_files: HashMap<String, CommitFiles>,
+    /// marks or original oids of blobs that were stripped.
+    /// any fileop that references one of these gets dropped.
+    pub stripped_blobs: HashSet<String>,
+    /// maps the mark of every blob we have seen to its original oid.
+    /// fileops only reference the mark, so this is needed to
+    /// know which blob they point to.
+    pub blob_mark_to_oid: HashMap<String, String>,
+    /// every (filtered) path that had a modification dropped
+    /// because its blob was stripped.
+    pub stripped_paths: BTreeSet<String>,
+    /// maps the mark of every commit we have seen (kept or not)
+    /// to its original oid.
+    pub commit_mark_to_oid: HashMap<String, String>,
+    /// the reverse of commit_mark_to_oid. sorted so
+    /// that abbreviated oids can be looked up.
+    pub commit_oid_to_mark: BTreeMap<String, String>,
+    /// maps the mark of a kept commit to its oid after being
+    /// imported. this is only filled in for commits that
+    /// we had to look up, see `commit_hashes`
+    pub new_commit_oids: HashMap<String, String>,
+    /// the last thing that each ref pointed to in the original history
+    pub ref_tips: BTreeMap<String, RefTip>,
+    /// the mark of the last kept commit (or reset)
+    /// that was written for each ref
+    pub last_kept_marks: HashMap<String, String>,
+    /// original oids of commits that an earlier run pruned, and
+    /// whose nearest kept ancestor we dont know
+    pub pruned_previous_commits: HashSet<String>,
 }
 
 impl FilterState {
@@ -15,4 +98,262 @@ impl FilterState {
             None => false,
         }
     }
+
+    /// when only part of the history is exported, the parents that were
+    /// not exported are raw oids. this lets them be rewired to the commits
+    /// they were rewritten to in an earlier run. the commit_map is
+    /// old oid -> new oid, see `commit_map::read_commit_map`
+    pub fn load_previous_commit_map(&mut self, commit_map: &HashMap<String, String>) {
+        for (old_oid, new_oid) in commit_map {
+            // it was pruned, and we dont know what its nearest kept
+            // ancestor was. see `commit_map::add_pruned_ref_tips`
+            if is_zero_oid(new_oid) {
+                self.pruned_previous_commits.insert(old_oid.clone());
+            } else {
+                self.mark_map.insert(old_oid.clone(), new_oid.clone());
+            }
+        }
+    }
+
+    /// remember the original oid of this commit, and that its ref
+    /// points to it. should be called before it is filtered.
+    pub fn record_commit(&mut self, commit: &StructuredCommit) {
+        if let Some(mark) = &commit.mark {
+            self.commit_mark_to_oid.insert(mark.clone(), commit.original_oid.clone());
+            self.commit_oid_to_mark.insert(commit.original_oid.clone(), mark.clone());
+        }
+        self.ref_tips.insert(commit.commit_ref.clone(), RefTip {
+            original_oid: commit.original_oid.clone(),
+            mark: commit.mark.clone(),
+        });
+    }
+
+    /// remember that this annotated tag's ref points to it
+    pub fn record_tag(&mut self, tag: &StructuredTag) {
+        if let Some(oid) = &tag.original_oid {
+            self.ref_tips.insert(format!("refs/tags/{}", tag.tag_name), RefTip {
+                original_oid: oid.clone(),
+                mark: None,
+            });
+        }
+    }
+
+    /// remember that a `reset <ref>` with `from <from>` points the ref at
+    /// the from, which is either a mark, or an oid
+    pub fn record_reset(&mut self, reset_ref: &str, from: &str) {
+        let tip = if from.starts_with(':') {
+            match self.commit_mark_to_oid.get(from) {
+                Some(oid) => RefTip { original_oid: oid.clone(), mark: Some(from.to_string()) },
+                None => return,
+            }
+        } else {
+            RefTip { original_oid: from.to_string(), mark: None }
+        };
+        self.ref_tips.insert(reset_ref.to_string(), tip);
+    }
+
+    /// the mark of the commit whose original oid starts with this prefix.
+    /// returns None if no commit, or more than one commit, matches
+    pub fn find_commit_by_oid_prefix(&self, prefix: &str) -> Option<&str> {
+        let mut matches = self.commit_oid_to_mark.range(prefix.to_string()..)
+            .take_while(|(oid, _)| oid.starts_with(prefix));
+        let (_, mark) = matches.next()?;
+        if matches.next().is_some() {
+            return None;
+        }
+        Some(mark)
+    }
+
+    /// every (ref, mark) where the original tip of the ref was dropped,
+    /// and the ref is not already at the nearest kept ancestor of that tip.
+    /// refs where every commit was dropped are not included.
+    pub fn refs_to_reset(&self) -> Vec<(String, String)> {
+        let mut resets = vec![];
+        for (ref_name, tip) in &self.ref_tips {
+            let mark = match &tip.mark {
+                Some(mark) => mark,
+                None => continue,
+            };
+            let kept_ancestor = match self.mark_map.get(mark) {
+                Some(m) if !m.is_empty() => m,
+                _ => continue,
+            };
+            if self.last_kept_marks.get(ref_name) != Some(kept_ancestor) {
+                resets.push((ref_name.clone(), kept_ancestor.clone()));
+            }
+        }
+        resets
+    }
+
+    /// record that this commit was dropped. any commit that
+    /// uses it as a parent will use its (kept) parent instead.
+    /// if it has no parent that we kept, it maps to an empty mark.
+    pub fn map_dropped_commit(&mut self, mark: &str, from: Option<&str>) {
+        let transitive_parent = match from {
+            Some(from) => self.mark_map.get(from).cloned(),
+            None => None,
+        };
+        let mapto = transitive_parent.unwrap_or_default();
+        self.mark_map.insert(mark.to_string(), mapto);
+    }
+
+    /// returns true if the ancestor mark is reachable from
+    /// the `of` mark by following the parents in the graph.
+    pub fn is_ancestor(&self, ancestor: &str, of: &str) -> bool {
+        let mut visited: HashSet<&str> = HashSet::new();
+        let mut to_visit = vec![of];
+        while let Some(mark) = to_visit.pop() {
+            let parents = match self.graph.get(mark) {
+                Some(parents) => parents,
+                None => continue,
+            };
+            for parent in parents {
+                if parent == ancestor {
+                    return true;
+                }
+                if visited.insert(parent.as_str()) {
+                    to_visit.push(parent.as_str());
+                }
+            }
+        }
+        false
+    }
+
+    /// the dataref can be a mark, or a raw oid if
+    /// fast-export was run without the blob data.
+    pub fn is_stripped_blob(&self, dataref: &str) -> bool {
+        if self.stripped_blobs.contains(dataref) {
+            return true;
+        }
+        match self.blob_mark_to_oid.get(dataref) {
+            Some(oid) => self.stripped_blobs.contains(oid),
+            None => false,
+        }
+    }
+
+    /// start tracking the files of a new commit, whose
+    /// files start out the same as the files of its from.
+    /// the other parents of a merge stop being tips, so
+    /// their snapshots are dropped to save memory.
+    pub fn start_commit_files(&mut self, from: Option<&str>, merges: &[String]) {
+        self.parent_files = match from {
+            Some(from) => match self.tip_files.remove(from) {
+                Some(files) => files,
+                // the from already has a child, so this is a new
+                // branch off of it, and we have to rebuild its files
+                None => self.commit_files(from),
+            },
+            None => CommitFiles::default(),
+        };
+        for merge in merges {
+            self.tip_files.remove(merge);
+        }
+        self.current_files = FileChanges {
+            parent: from.map(|f| f.to_string()),
+            ..Default::default()
+        };
+    }
+
+    /// the commit is done, so remember its changes in case
+    /// another commit uses it as a parent, and make its files
+    /// the snapshot of this tip
+    pub fn finish_commit_files(&mut self, mark: Option<&str>) {
+        let changes = std::mem::take(&mut self.current_files);
+        let mut files = std::mem::take(&mut self.parent_files);
+        if let Some(mark) = mark {
+            apply_file_changes(&mut files, &changes);
+            self.tip_files.insert(mark.to_string(), files);
+            self.file_changes.insert(mark.to_string(), changes);
+        }
+    }
+
+    /// all of the files of this commit. walks back through the
+    /// changes until a snapshot (or a deleteall), and then
+    /// replays the changes forward from there
+    fn commit_files(&self, mark: &str) -> CommitFiles {
+        let mut files = CommitFiles::default();
+        let mut chain = vec![];
+        let mut next = Some(mark);
+        while let Some(mark) = next {
+            if let Some(snapshot) = self.tip_files.get(mark) {
+                files = snapshot.clone();
+                break;
+            }
+            // the parent might not be in the stream, (eg: an
+            // excluded parent) so we cant know what it had
+            let changes = match self.file_changes.get(mark) {
+                Some(changes) => changes,
+                None => break,
+            };
+            chain.push(changes);
+            if changes.deleted_all {
+                break;
+            }
+            next = changes.parent.as_deref();
+        }
+        for changes in chain.into_iter().rev() {
+            apply_file_changes(&mut files, changes);
+        }
+        files
+    }
+
+    /// the (mode, dataref) of this original path in the commit
+    /// that is currently being filtered, if it exists
+    pub fn get_file(&self, path: &str) -> Option<&(String, String)> {
+        if let Some(info) = self.current_files.files.get(path) {
+            return info.as_ref();
+        }
+        if self.current_files.deleted_all {
+            return None;
+        }
+        self.parent_files.get(path)
+    }
+
+    /// the (mode, dataref) of this original path in the
+    /// commit with this mark, if it exists
+    pub fn get_commit_file(&self, mark: &str, path: &str) -> Option<&(String, String)> {
+        let mut mark = mark;
+        loop {
+            if let Some(snapshot) = self.tip_files.get(mark) {
+                return snapshot.get(path);
+            }
+            let changes = self.file_changes.get(mark)?;
+            if let Some(info) = changes.files.get(path) {
+                return info.as_ref();
+            }
+            if changes.deleted_all {
+                return None;
+            }
+            mark = changes.parent.as_ref()?;
+        }
+    }
+
+    /// update the current_files to reflect this original fileop.
+    /// this should be called with the fileops before they are filtered.
+    pub fn track_fileop(&mut self, fileop: &FileOpsOwned) {
+        match fileop {
+            FileOpsOwned::FileModify(mode, dataref, path) => {
+                self.current_files.files.insert(path.clone(), Some((mode.clone(), dataref.clone())));
+            }
+            FileOpsOwned::FileDelete(path) => {
+                self.current_files.files.insert(path.clone(), None);
+            }
+            FileOpsOwned::FileCopy(src, dest) => {
+                if let Some(info) = self.get_file(src).cloned() {
+                    self.current_files.files.insert(dest.clone(), Some(info));
+                }
+            }
+            FileOpsOwned::FileRename(src, dest) => {
+                if let Some(info) = self.get_file(src).cloned() {
+                    self.current_files.files.insert(src.clone(), None);
+                    self.current_files.files.insert(dest.clone(), Some(info));
+                }
+            }
+            FileOpsOwned::FileDeleteAll => {
+                self.current_files.files.clear();
+                self.current_files.deleted_all = true;
+            }
+            FileOpsOwned::NoteModify(_, _) => {}
+        }
+    }
 }
@@ -2,3 +2,9 @@
 pub mod export_parser;
 pub mod filter;
 pub mod filter_state;
+pub mod filter_pattern;
+pub mod blob_store;
+pub mod replace_text;
+pub mod mailmap;
+pub mod commit_map;
+pub mod commit_hashes;
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use super::export_parser::{CommitPersonOwned, StructuredCommit, StructuredTag, AuthorPerson};
+
+/// the proper name and/or email to replace with
+#[derive(Debug, Default, Clone)]
+struct MailmapReplacement {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+/// everything in the mailmap for one commit email
+#[derive(Debug, Default)]
+struct MailmapEntry {
+    /// used when the commit name does not match any of by_name
+    replacement: MailmapReplacement,
+    /// lowercase commit name -> replacement. used when both
+    /// the commit name and commit email match
+    by_name: HashMap<String, MailmapReplacement>,
+}
+
+/// maps commit names/emails to proper names/emails the same way
+/// that git's `.mailmap` does. see `git help gitmailmap`.
+#[derive(Debug, Default)]
+pub struct Mailmap {
+    /// lowercase commit email -> entry
+    entries: HashMap<String, MailmapEntry>,
+}
+
+/// parse `Some Name <some@email>` from the start of the text,
+/// and return the rest of the text after it.
+/// the name is None if there is only whitespace before the `<`
+fn parse_name_and_email(text: &str) -> Option<(Option<&str>, &str, &str)> {
+    let left = text.find('<')?;
+    let right = left + text[left..].find('>')?;
+    let name = text[..left].trim();
+    let name = if name.is_empty() { None } else { Some(name) };
+    let email = &text[left + 1..right];
+    Some((name, email, &text[right + 1..]))
+}
+
+impl Mailmap {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// parse the `.mailmap` format. each line is one of:
+    /// - `Proper Name <commit@email>`
+    /// - `<proper@email> <commit@email>`
+    /// - `Proper Name <proper@email> <commit@email>`
+    /// - `Proper Name <proper@email> Commit Name <commit@email>`
+    ///
+    /// lines starting with `#` and lines that dont parse are ignored, like git does.
+    pub fn parse_from_str(text: &str) -> Mailmap {
+        let mut mailmap = Mailmap::default();
+        for line in text.lines() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let (name1, email1, rest) = match parse_name_and_email(line) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            match parse_name_and_email(rest) {
+                Some((name2, email2, _)) => {
+                    let replacement = MailmapReplacement {
+                        name: name1.map(|n| n.to_string()),
+                        email: Some(email1.to_string()),
+                    };
+                    mailmap.add_mapping(name2, email2, replacement);
+                }
+                None => {
+                    // theres only one email, so its the commit email,
+                    // and we only replace the name
+                    let replacement = MailmapReplacement {
+                        name: name1.map(|n| n.to_string()),
+                        email: None,
+                    };
+                    mailmap.add_mapping(None, email1, replacement);
+                }
+            }
+        }
+        mailmap
+    }
+
+    fn add_mapping(&mut self, commit_name: Option<&str>, commit_email: &str, replacement: MailmapReplacement) {
+        let entry = self.entries.entry(commit_email.to_lowercase()).or_default();
+        let existing = match commit_name {
+            Some(name) => entry.by_name.entry(name.to_lowercase()).or_default(),
+            None => &mut entry.replacement,
+        };
+        // later lines only override what they provide
+        if replacement.name.is_some() {
+            existing.name = replacement.name;
+        }
+        if replacement.email.is_some() {
+            existing.email = replacement.email;
+        }
+    }
+
+    /// read a file in the `.mailmap` format
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> io::Result<Mailmap> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(Mailmap::parse_from_str(&text))
+    }
+
+    /// read the `.mailmap` of the repository at the location (or the
+    /// current directory). if there is no `.mailmap` in the working tree,
+    /// the one committed at HEAD is used, so this works for bare repos too.
+    /// if neither exist, the mailmap is empty.
+    pub fn load_from_repo<P: AsRef<Path>>(location: Option<P>) -> io::Result<Mailmap> {
+        let worktree_file = match &location {
+            Some(l) => l.as_ref().join(".mailmap"),
+            None => Path::new(".mailmap").to_path_buf(),
+        };
+        if worktree_file.is_file() {
+            return Mailmap::load_from_file(worktree_file);
+        }
+
+        let exe_and_args = ["git", "cat-file", "blob", "HEAD:.mailmap"];
+        let child = exechelper::spawn_with_env_ex2(
+            &exe_and_args,
+            &[], &[],
+            location,
+            Some(Stdio::null()),
+            Some(Stdio::null()),
+            Some(Stdio::piped()),
+        )?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Ok(Mailmap::default());
+        }
+        Ok(Mailmap::parse_from_str(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    fn find_replacement(&self, name: Option<&str>, email: &str) -> Option<&MailmapReplacement> {
+        let entry = self.entries.get(&email.to_lowercase())?;
+        if let Some(name) = name {
+            if let Some(replacement) = entry.by_name.get(&name.to_lowercase()) {
+                return Some(replacement);
+            }
+        }
+        let replacement = &entry.replacement;
+        if replacement.name.is_none() && replacement.email.is_none() {
+            return None;
+        }
+        Some(replacement)
+    }
+
+    /// rewrite the name and email of this person.
+    /// returns true if anything changed.
+    pub fn map_person(&self, person: &mut CommitPersonOwned) -> bool {
+        let replacement = match self.find_replacement(person.name.as_deref(), &person.email) {
+            Some(r) => r.clone(),
+            None => return false,
+        };
+        let mut changed = false;
+        if let Some(name) = replacement.name {
+            changed = changed || person.name.as_ref() != Some(&name);
+            person.name = Some(name);
+        }
+        if let Some(email) = replacement.email {
+            changed = changed || person.email != email;
+            person.email = email;
+        }
+        changed
+    }
+
+    /// rewrite the author and committer of this commit
+    pub fn map_commit(&self, commit: &mut StructuredCommit) {
+        // the author might only be the same as the committer
+        // before mapping, so map it separately, and then
+        // check if they are still the same afterwards
+        let author = std::mem::take(&mut commit.author);
+        let mut author = match author {
+            AuthorPerson::SameAsCommitPerson => AuthorPerson::Author(commit.committer.clone()),
+            other => other,
+        };
+        if let AuthorPerson::Author(ref mut a) = author {
+            self.map_person(a);
+        }
+        self.map_person(&mut commit.committer);
+        commit.author = match author {
+            AuthorPerson::Author(a) if a == commit.committer => AuthorPerson::SameAsCommitPerson,
+            other => other,
+        };
+    }
+
+    /// rewrite the tagger of this tag
+    pub fn map_tag(&self, tag: &mut StructuredTag) {
+        if let Some(tagger) = &mut tag.tagger {
+            self.map_person(tagger);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn person(name: &str, email: &str) -> CommitPersonOwned {
+        CommitPersonOwned {
+            name: Some(name.into()),
+            email: email.into(),
+            timestr: "1792312127 +0000".into(),
+        }
+    }
+
+    #[test]
+    fn follows_git_mailmap_rules() {
+        let text = "
+# comment
+Proper Name <commit@example.com>
+<proper@example.com> <OLD@example.com>
+Jane Doe <jane@example.com> <jdoe@example.com>
+Joe Dev <joe@example.com> joe <shared@example.com>
+Other Dev <other@example.com> Other <shared@example.com>
+";
+        let mailmap = Mailmap::parse_from_str(text);
+
+        // name only
+        let mut p = person("whoever", "commit@example.com");
+        assert!(mailmap.map_person(&mut p));
+        assert_eq!(p, person("Proper Name", "commit@example.com"));
+
+        // email only, and emails are case insensitive
+        let mut p = person("whoever", "old@example.com");
+        assert!(mailmap.map_person(&mut p));
+        assert_eq!(p, person("whoever", "proper@example.com"));
+
+        // name and email
+        let mut p = person("jd", "jdoe@example.com");
+        assert!(mailmap.map_person(&mut p));
+        assert_eq!(p, person("Jane Doe", "jane@example.com"));
+
+        // the name and the email have to match
+        let mut p = person("Joe", "shared@example.com");
+        assert!(mailmap.map_person(&mut p));
+        assert_eq!(p, person("Joe Dev", "joe@example.com"));
+        let mut p = person("other", "shared@example.com");
+        assert!(mailmap.map_person(&mut p));
+        assert_eq!(p, person("Other Dev", "other@example.com"));
+        let mut p = person("nobody", "shared@example.com");
+        assert!(!mailmap.map_person(&mut p));
+        assert_eq!(p, person("nobody", "shared@example.com"));
+    }
+
+    #[test]
+    fn same_as_commit_person_stays_correct() {
+        let mailmap = Mailmap::parse_from_str("Proper Name <author@example.com>");
+
+        // same before mapping, same after
+        let mut commit = StructuredCommit::default();
+        commit.committer = person("a", "author@example.com");
+        commit.author = AuthorPerson::SameAsCommitPerson;
+        mailmap.map_commit(&mut commit);
+        assert!(matches!(commit.author, AuthorPerson::SameAsCommitPerson));
+        assert_eq!(commit.committer, person("Proper Name", "author@example.com"));
+
+        // different before mapping, same after
+        let mut commit = StructuredCommit::default();
+        commit.committer = person("Proper Name", "author@example.com");
+        commit.author = AuthorPerson::Author(person("a", "author@example.com"));
+        mailmap.map_commit(&mut commit);
+        assert!(matches!(commit.author, AuthorPerson::SameAsCommitPerson));
+
+        // different before and after
+        let mut commit = StructuredCommit::default();
+        commit.committer = person("c", "committer@example.com");
+        commit.author = AuthorPerson::Author(person("a", "author@example.com"));
+        mailmap.map_commit(&mut commit);
+        assert_eq!(commit.get_author(), Some(&person("Proper Name", "author@example.com")));
+        assert_eq!(commit.committer, person("c", "committer@example.com"));
+    }
+}
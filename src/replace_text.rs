@@ -0,0 +1,254 @@
+use regex::bytes::{Regex, NoExpand};
+use std::io;
+use std::path::Path;
+use super::export_parser::StructuredBlob;
+use super::filter::{FilterError, convert_python_replacement};
+use super::filter_pattern;
+
+/// what a match is replaced with if the rule doesnt say
+pub const DEFAULT_REPLACEMENT: &str = "***REMOVED***";
+
+/// git considers data binary if there is a NUL byte
+/// in the first 8000 bytes. we do the same.
+const BINARY_CHECK_LEN: usize = 8000;
+
+#[derive(Debug)]
+struct ReplaceTextRule {
+    regex: Regex,
+    replacement: Vec<u8>,
+    /// literal rules should not expand `$1` in the replacement
+    expand_groups: bool,
+}
+
+/// a list of text replacements, like git-filter-repo's `--replace-text`.
+/// the replacements work on bytes, so blobs that are not utf-8
+/// are still replaced correctly.
+#[derive(Debug, Default)]
+pub struct ReplaceText {
+    rules: Vec<ReplaceTextRule>,
+    /// if true, dont replace anything in blobs that look binary
+    pub skip_binary: bool,
+}
+
+/// returns true if the data has a NUL byte near the start
+pub fn is_binary(data: &[u8]) -> bool {
+    let check_len = std::cmp::min(data.len(), BINARY_CHECK_LEN);
+    data[..check_len].contains(&0)
+}
+
+/// convert a glob into a regex string that can match anywhere in
+/// some text. unlike path globs, `*` and `?` also match a `/`.
+pub fn text_glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' => out.push_str(".*"),
+            '?' => out.push('.'),
+            '[' => {
+                match filter_pattern::glob_class_to_regex(&chars[i..]) {
+                    Some((class_str, consumed)) => {
+                        out.push_str(&class_str);
+                        i += consumed;
+                        continue;
+                    }
+                    None => out.push_str("\\["),
+                }
+            }
+            c => out.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    out
+}
+
+impl ReplaceText {
+    pub fn new() -> ReplaceText {
+        ReplaceText::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn add_rule(&mut self, regex_str: &str, replacement: &str, expand_groups: bool) -> Result<(), FilterError> {
+        let regex = Regex::new(regex_str)
+            .map_err(|e| FilterError(format!("Invalid replace text pattern: {}\n{}", regex_str, e)))?;
+        self.rules.push(ReplaceTextRule {
+            regex,
+            replacement: replacement.as_bytes().to_vec(),
+            expand_groups,
+        });
+        Ok(())
+    }
+
+    /// replace every occurrence of the literal text
+    pub fn add_literal(&mut self, literal: &str, replacement: &str) -> Result<(), FilterError> {
+        self.add_rule(&regex::escape(literal), replacement, false)
+    }
+
+    /// replace every match of the glob. see `text_glob_to_regex`
+    pub fn add_glob(&mut self, glob: &str, replacement: &str) -> Result<(), FilterError> {
+        self.add_rule(&text_glob_to_regex(glob), replacement, false)
+    }
+
+    /// replace every match of the regex. the replacement can
+    /// reference capture groups with `$1`, `${1}` or `\1`
+    pub fn add_regex(&mut self, regex_str: &str, replacement: &str) -> Result<(), FilterError> {
+        self.add_rule(regex_str, &convert_python_replacement(replacement), true)
+    }
+
+    /// parse replacements in the same format as
+    /// git-filter-repo's `--replace-text`. one per line:
+    /// - `password123` replaces it with `***REMOVED***`
+    /// - `password123==>xxx` replaces it with `xxx`
+    /// - `glob:` and `regex:` prefixes match a glob or regex
+    ///   instead of literal text, eg: `regex:api_key=\w+==>api_key=xxx`
+    ///
+    /// blank lines and lines starting with `#` are ignored.
+    pub fn parse_from_str(text: &str) -> Result<ReplaceText, FilterError> {
+        let mut replace_text = ReplaceText::new();
+        for (line_index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // the pattern can contain ==>, but the replacement cannot
+            let (pattern, replacement) = match line.rfind("==>") {
+                Some(split_at) => (&line[..split_at], &line[split_at + 3..]),
+                None => (line, DEFAULT_REPLACEMENT),
+            };
+            let make_err = |reason: &str| {
+                FilterError(format!("Invalid replacement on line {}: {}\n{}", line_index + 1, reason, line))
+            };
+            let (kind, pattern) = if let Some(glob) = pattern.strip_prefix("glob:") {
+                ("glob", glob)
+            } else if let Some(regex_str) = pattern.strip_prefix("regex:") {
+                ("regex", regex_str)
+            } else {
+                ("literal", pattern.strip_prefix("literal:").unwrap_or(pattern))
+            };
+            if pattern.is_empty() {
+                return Err(make_err("missing pattern"));
+            }
+            let res = match kind {
+                "glob" => replace_text.add_glob(pattern, replacement),
+                "regex" => replace_text.add_regex(pattern, replacement),
+                _ => replace_text.add_literal(pattern, replacement),
+            };
+            if let Err(e) = res {
+                return Err(make_err(&e.0));
+            }
+        }
+        Ok(replace_text)
+    }
+
+    /// read a file with the same format as
+    /// git-filter-repo's `--replace-text`.
+    /// see `parse_from_str`
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> io::Result<ReplaceText> {
+        let text = std::fs::read_to_string(path)?;
+        let replace_text = ReplaceText::parse_from_str(&text)?;
+        Ok(replace_text)
+    }
+
+    /// apply every rule in order. returns None if nothing was replaced
+    pub fn replace(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut replaced: Option<Vec<u8>> = None;
+        for rule in &self.rules {
+            let current = match &replaced {
+                Some(r) => r.as_slice(),
+                None => data,
+            };
+            if !rule.regex.is_match(current) {
+                continue;
+            }
+            let new_data = if rule.expand_groups {
+                rule.regex.replace_all(current, rule.replacement.as_slice()).into_owned()
+            } else {
+                rule.regex.replace_all(current, NoExpand(&rule.replacement)).into_owned()
+            };
+            replaced = Some(new_data);
+        }
+        replaced
+    }
+
+    /// replace the data of the blob in place.
+    /// returns true if the data changed.
+    pub fn replace_blob(&self, blob: &mut StructuredBlob) -> bool {
+        if self.skip_binary && is_binary(&blob.data) {
+            return false;
+        }
+        match self.replace(&blob.data) {
+            Some(new_data) => {
+                blob.data = new_data;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// replace a commit or tag message in place.
+    /// returns true if the message changed.
+    pub fn replace_message(&self, message: &mut String) -> bool {
+        match self.replace(message.as_bytes()) {
+            Some(new_data) => {
+                *message = match String::from_utf8(new_data) {
+                    Ok(s) => s,
+                    Err(e) => String::from_utf8_lossy(e.as_bytes()).into(),
+                };
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn can_parse_and_replace() {
+        let text = "
+# comment
+password123
+token==>[token]
+glob:AKIA????????==>AWS_KEY
+regex:secret=(\\w+)==>secret=\\1_gone
+";
+        let replace_text = ReplaceText::parse_from_str(text).unwrap();
+        let data = b"a password123 token AKIA12345678 secret=abc $1".to_vec();
+        let replaced = replace_text.replace(&data).unwrap();
+        assert_eq!(
+            String::from_utf8(replaced).unwrap(),
+            "a ***REMOVED*** [token] AWS_KEY secret=abc_gone $1",
+        );
+        assert!(replace_text.replace(b"nothing to see").is_none());
+        assert!(ReplaceText::parse_from_str("regex:a(b").is_err());
+    }
+
+    #[test]
+    fn works_on_non_utf8_and_can_skip_binary() {
+        let mut replace_text = ReplaceText::new();
+        replace_text.add_literal("key", "xxx").unwrap();
+        let mut blob = StructuredBlob {
+            data: vec![0xff, b'k', b'e', b'y', 0xfe],
+            ..Default::default()
+        };
+        assert!(replace_text.replace_blob(&mut blob));
+        assert_eq!(blob.data, vec![0xff, b'x', b'x', b'x', 0xfe]);
+
+        replace_text.skip_binary = true;
+        let mut blob = StructuredBlob {
+            data: vec![0, b'k', b'e', b'y'],
+            ..Default::default()
+        };
+        assert!(!replace_text.replace_blob(&mut blob));
+        assert_eq!(blob.data, vec![0, b'k', b'e', b'y']);
+
+        let mut message = String::from("add key\n");
+        assert!(replace_text.replace_message(&mut message));
+        assert_eq!(message, "add xxx\n");
+    }
+}